@@ -0,0 +1,322 @@
+use std::{
+    collections::VecDeque,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+
+use crate::{
+    control::{Control, ControlSender},
+    midi::MidiMessage,
+    tempo::clamp_bpm,
+    SampleData,
+};
+
+/// MIDI clock resolution, in ticks per quarter note
+pub(crate) const PPQ: f64 = 24.0;
+
+/// How far the position is extrapolated past the last callback, in case it stalls
+const MAX_EXTRAPOLATION: Duration = Duration::from_millis(100);
+
+/// Ticks behind after which the generator jumps ahead instead of catching up
+const MAX_CATCH_UP: u64 = 24;
+
+/// Ticks the follower averages, two beats
+const FOLLOW_WINDOW: usize = 48;
+
+/// Smallest tempo change reported by the follower, to ignore the jitter of incoming clocks
+const FOLLOW_THRESHOLD: f32 = 0.2;
+
+/// Incoming clocks further apart than this are considered stopped
+const FOLLOW_TIMEOUT: Duration = Duration::from_millis(500);
+
+/// Musical position published by the audio callback for the clock output
+pub(crate) struct ClockPosition {
+    epoch: Instant,
+    /// `f64` bits of the beats played at `at`
+    beats: AtomicU64,
+    /// Nanoseconds since `epoch`
+    at: AtomicU64,
+    running: AtomicBool,
+}
+
+impl ClockPosition {
+    pub fn new() -> Self {
+        Self {
+            epoch: Instant::now(),
+            beats: AtomicU64::new(0f64.to_bits()),
+            at: AtomicU64::new(0),
+            running: AtomicBool::new(false),
+        }
+    }
+
+    /// Called by the callback with the position at `now`, `running` once something plays
+    pub fn publish(&self, beats: f64, now: Instant, running: bool) {
+        // both values may be read a callback apart, which the extrapolation doesn't mind
+        self.beats.store(beats.to_bits(), Ordering::Relaxed);
+        self.at.store(
+            now.duration_since(self.epoch).as_nanos() as u64,
+            Ordering::Relaxed,
+        );
+        self.running.store(running, Ordering::Relaxed);
+    }
+
+    /// Beats played at `now` at the given tempo, none when stopped
+    pub fn beats_at(&self, now: Instant, bpm: f32) -> Option<f64> {
+        if !self.running.load(Ordering::Relaxed) {
+            return None;
+        }
+
+        let beats = f64::from_bits(self.beats.load(Ordering::Relaxed));
+        let at = self.epoch + Duration::from_nanos(self.at.load(Ordering::Relaxed));
+        let elapsed = now.saturating_duration_since(at).min(MAX_EXTRAPOLATION);
+        Some(beats + elapsed.as_secs_f64() * bpm as f64 / 60.0)
+    }
+}
+
+/// Turns the musical position into MIDI clock messages
+#[derive(Default)]
+pub(crate) struct ClockGenerator {
+    running: bool,
+    started: bool,
+    next_tick: u64,
+}
+
+impl ClockGenerator {
+    /// Emit the messages due at `beats`, none meaning the playback is stopped
+    pub fn update(&mut self, beats: Option<f64>, mut emit: impl FnMut(MidiMessage)) {
+        let Some(beats) = beats else {
+            if self.running {
+                self.running = false;
+                emit(MidiMessage::Stop);
+            }
+            return;
+        };
+
+        let tick = (beats.max(0.0) * PPQ).floor() as u64;
+        if !self.running {
+            self.running = true;
+            if self.started {
+                emit(MidiMessage::Continue);
+            } else {
+                self.started = true;
+                self.next_tick = tick;
+                emit(MidiMessage::Start);
+            }
+        }
+
+        if tick > self.next_tick + MAX_CATCH_UP {
+            self.next_tick = tick;
+        }
+        while self.next_tick <= tick {
+            emit(MidiMessage::Clock);
+            self.next_tick += 1;
+        }
+    }
+}
+
+/// Derives the tempo from the times incoming clock ticks are received
+#[derive(Default)]
+pub(crate) struct BpmEstimator {
+    ticks: VecDeque<Instant>,
+    reported: Option<f32>,
+}
+
+impl BpmEstimator {
+    /// Forget the ticks received so far, e.g. when the clock stops
+    pub fn reset(&mut self) {
+        self.ticks.clear();
+    }
+
+    /// Register a tick, returning the new tempo when it changed noticeably
+    pub fn tick(&mut self, now: Instant) -> Option<f32> {
+        if self
+            .ticks
+            .back()
+            .is_some_and(|last| now.duration_since(*last) > FOLLOW_TIMEOUT)
+        {
+            self.reset();
+        }
+        if self.ticks.len() > FOLLOW_WINDOW {
+            self.ticks.pop_front();
+        }
+        self.ticks.push_back(now);
+
+        // wait for a whole beat before estimating anything
+        let intervals = self.ticks.len() - 1;
+        if intervals < PPQ as usize {
+            return None;
+        }
+        // least squares fit of the tick times, less sensitive to jitter than the first and last
+        let first = *self.ticks.front()?;
+        let count = self.ticks.len() as f64;
+        let mean_index = (count - 1.0) / 2.0;
+        let mean_time = self
+            .ticks
+            .iter()
+            .map(|at| at.duration_since(first).as_secs_f64())
+            .sum::<f64>()
+            / count;
+        let (covariance, variance) =
+            self.ticks
+                .iter()
+                .enumerate()
+                .fold((0.0, 0.0), |(covariance, variance), (i, at)| {
+                    let index = i as f64 - mean_index;
+                    let time = at.duration_since(first).as_secs_f64() - mean_time;
+                    (covariance + index * time, variance + index * index)
+                });
+        let tick = covariance / variance;
+        let bpm = (clamp_bpm((60.0 / (tick * PPQ)) as f32) * 10.0).round() / 10.0;
+
+        if self
+            .reported
+            .is_some_and(|reported| (bpm - reported).abs() < FOLLOW_THRESHOLD)
+        {
+            return None;
+        }
+        self.reported = Some(bpm);
+        Some(bpm)
+    }
+}
+
+/// Follows the clock of another device: its tempo, start and stop
+pub(crate) struct ClockFollower {
+    estimator: BpmEstimator,
+    control: ControlSender,
+    sample_data: Arc<SampleData>,
+}
+
+impl ClockFollower {
+    pub fn new(control: ControlSender, sample_data: Arc<SampleData>) -> Self {
+        Self {
+            estimator: BpmEstimator::default(),
+            control,
+            sample_data,
+        }
+    }
+
+    pub fn handle(&mut self, message: MidiMessage, now: Instant) {
+        match message {
+            MidiMessage::Clock => {
+                if let Some(bpm) = self.estimator.tick(now) {
+                    self.control.send(Control::SetBpm(bpm));
+                }
+            }
+            MidiMessage::Start | MidiMessage::Continue => {
+                self.sample_data.paused.store(false, Ordering::Relaxed);
+            }
+            MidiMessage::Stop => {
+                self.estimator.reset();
+                self.sample_data.paused.store(true, Ordering::Relaxed);
+            }
+            _ => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{BpmEstimator, ClockGenerator, ClockPosition};
+
+    use std::time::{Duration, Instant};
+
+    use crate::{midi::MidiMessage, quantize::Transport};
+
+    fn generate(generator: &mut ClockGenerator, beats: Option<f64>) -> Vec<MidiMessage> {
+        let mut messages = vec![];
+        generator.update(beats, |message| messages.push(message));
+        messages
+    }
+
+    #[test]
+    fn generate_ticks_start_and_stop() {
+        let mut generator = ClockGenerator::default();
+        assert_eq!(generate(&mut generator, None), []);
+
+        assert_eq!(
+            generate(&mut generator, Some(0.0)),
+            [MidiMessage::Start, MidiMessage::Clock]
+        );
+        assert_eq!(generate(&mut generator, Some(0.02)), []);
+        assert_eq!(
+            generate(&mut generator, Some(0.1)),
+            [MidiMessage::Clock, MidiMessage::Clock]
+        );
+
+        assert_eq!(generate(&mut generator, None), [MidiMessage::Stop]);
+        assert_eq!(
+            generate(&mut generator, Some(0.13)),
+            [MidiMessage::Continue, MidiMessage::Clock]
+        );
+
+        // far behind, e.g. after the machine was suspended
+        assert_eq!(generate(&mut generator, Some(100.0)), [MidiMessage::Clock]);
+    }
+
+    #[test]
+    fn extrapolate_position() {
+        let position = ClockPosition::new();
+        let now = Instant::now();
+        assert_eq!(position.beats_at(now, 120.0), None);
+
+        position.publish(4.0, now, true);
+        let beats = position.beats_at(now + Duration::from_millis(50), 120.0);
+        assert!((beats.unwrap() - 4.1).abs() < 1e-6);
+        let beats = position.beats_at(now + Duration::from_secs(5), 120.0);
+        assert!((beats.unwrap() - 4.2).abs() < 1e-6);
+    }
+
+    #[test]
+    fn start_with_the_first_update() {
+        let mut transport = Transport::new(48000, 120.0);
+        let position = ClockPosition::new();
+        let mut generator = ClockGenerator::default();
+        let now = Instant::now();
+
+        // silence, then the first update 200 frames into a buffer of 512
+        transport.advance(512);
+        transport.start(200);
+        transport.advance(512);
+        position.publish(transport.beats(), now, true);
+
+        let beats = position.beats_at(now, 120.0).unwrap();
+        assert!((beats - 312.0 / 24000.0).abs() < 1e-9);
+        assert_eq!(
+            generate(&mut generator, Some(beats)),
+            [MidiMessage::Start, MidiMessage::Clock]
+        );
+        // the second tick comes a 24th of a beat, 1000 frames, after the first frame of the code
+        let second = position.beats_at(now + Duration::from_micros(14_200), 120.0);
+        assert_eq!(generate(&mut generator, second), []);
+        let second = position.beats_at(now + Duration::from_micros(14_400), 120.0);
+        assert_eq!(generate(&mut generator, second), [MidiMessage::Clock]);
+    }
+
+    #[test]
+    fn follow_tempo_changes() {
+        let mut estimator = BpmEstimator::default();
+        let mut now = Instant::now();
+        let mut reported = vec![];
+
+        // 120 BPM is a tick every 20.83ms, received with a millisecond of jitter
+        for i in 0..200 {
+            let jitter = if i % 2 == 0 { 1000 } else { -1000 };
+            let at = now + Duration::from_micros((20_833 + jitter) as u64);
+            reported.extend(estimator.tick(at));
+            now += Duration::from_micros(20_833);
+        }
+        assert_eq!(reported, [120.0]);
+
+        for _ in 0..200 {
+            now += Duration::from_micros(16_667);
+            reported.extend(estimator.tick(now));
+        }
+        // changes gradually, as the window slides
+        let last = *reported.last().unwrap();
+        assert!((last - 150.0).abs() < 0.2, "{reported:?}");
+        assert!(reported.windows(2).all(|w| w[0] < w[1]), "{reported:?}");
+    }
+}
@@ -0,0 +1,118 @@
+use std::sync::{Arc, Mutex};
+
+use glicol::Engine;
+use glicol_synth::{Message, Node};
+use ringbuf::{HeapConsumer, HeapProducer, HeapRb};
+use tracing::warn;
+
+use crate::BLOCK_SIZE;
+
+/// Changes queued before the callback picks them up, MIDI controllers can send a lot of them
+const CAPACITY: usize = 256;
+
+/// Constants the callback keeps track of, enough for every MIDI controller and a few tracks
+const MAX_CONSTANTS: usize = 256;
+
+/// Change requested to the audio callback while it plays
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum Control {
+    SetBpm(f32),
+    /// Set the value of a constant chain like `~cutoff: constsig 1000`
+    SetConstant(&'static str, f32),
+    /// Master volume, in dB
+    SetVolume(f32),
+    /// Mute or unmute the output
+    Mute(bool),
+}
+
+/// Create both ends of the control channel
+pub(crate) fn control_channel() -> (ControlSender, ControlReceiver) {
+    let (producer, consumer) = HeapRb::new(CAPACITY).split();
+    (
+        ControlSender(Arc::new(Mutex::new(producer))),
+        ControlReceiver(consumer),
+    )
+}
+
+/// Sending side, shared by the threads controlling the playback
+#[derive(Clone)]
+pub(crate) struct ControlSender(Arc<Mutex<HeapProducer<Control>>>);
+
+impl ControlSender {
+    pub fn send(&self, control: Control) {
+        let mut producer = self.0.lock().expect("poisoned lock");
+        if producer.push(control).is_err() {
+            warn!("audio thread is late, dropped {control:?}");
+        }
+    }
+}
+
+/// Audio callback side, never blocks
+pub(crate) struct ControlReceiver(HeapConsumer<Control>);
+
+impl ControlReceiver {
+    pub fn try_recv(&mut self) -> Option<Control> {
+        self.0.pop()
+    }
+}
+
+/// Set the value of a chain starting with a `constsig`, without rebuilding the graph
+///
+/// Does nothing when the code has no such chain.
+pub(crate) fn set_constant(engine: &mut Engine<BLOCK_SIZE>, name: &str, value: f32) {
+    if let Some(index) = engine.index_info.get(name).and_then(|chain| chain.first()) {
+        engine.context.graph[*index]
+            .node
+            .send_msg(Message::SetToNumber(0, value));
+    }
+}
+
+/// Values given to constants, applied again to the engines of later code updates
+pub(crate) struct Constants(Vec<(&'static str, f32)>);
+
+impl Constants {
+    pub fn new() -> Self {
+        Self(Vec::with_capacity(MAX_CONSTANTS))
+    }
+
+    /// Remember a value, new constants are ignored once full as growing would allocate
+    pub fn set(&mut self, name: &'static str, value: f32) {
+        if let Some((_, known_value)) = self.0.iter_mut().find(|(known, _)| *known == name) {
+            *known_value = value;
+        } else if self.0.len() < MAX_CONSTANTS {
+            self.0.push((name, value));
+        }
+    }
+
+    pub fn apply(&self, engine: &mut Engine<BLOCK_SIZE>) {
+        for (name, value) in &self.0 {
+            set_constant(engine, name, *value);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{set_constant, Constants};
+
+    use glicol::Engine;
+
+    use crate::BLOCK_SIZE;
+
+    #[test]
+    fn set_constants_of_running_engines() {
+        let mut engine = Engine::<BLOCK_SIZE>::new();
+        engine.update_with_code("~amp: constsig 1.0\no: constsig 0.5 >> mul ~amp");
+        assert_eq!(engine.next_block(vec![]).0[0][0], 0.5);
+
+        set_constant(&mut engine, "~amp", 0.5);
+        set_constant(&mut engine, "~missing", 0.5);
+        assert_eq!(engine.next_block(vec![]).0[0][0], 0.25);
+
+        let mut constants = Constants::new();
+        constants.set("~amp", 1.0);
+        constants.set("~amp", 2.0);
+        constants.apply(&mut engine);
+        assert_eq!(engine.next_block(vec![]).0[0][0], 1.0);
+    }
+}
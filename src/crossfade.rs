@@ -0,0 +1,55 @@
+use std::f32::consts::FRAC_PI_2;
+
+/// Equal-power fade from the engine being replaced to the new one
+pub(crate) struct Crossfade {
+    length: usize,
+    position: usize,
+}
+
+impl Crossfade {
+    pub fn new(length: usize) -> Self {
+        Self {
+            length,
+            position: 0,
+        }
+    }
+
+    pub fn is_done(&self) -> bool {
+        self.position >= self.length
+    }
+
+    /// Gains of the old and new engines for the next frame
+    pub fn next_gains(&mut self) -> (f32, f32) {
+        if self.is_done() {
+            return (0.0, 1.0);
+        }
+
+        let t = (self.position + 1) as f32 / self.length as f32;
+        self.position += 1;
+        ((t * FRAC_PI_2).cos(), (t * FRAC_PI_2).sin())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Crossfade;
+
+    #[test]
+    fn keep_power_while_fading() {
+        let mut fade = Crossfade::new(100);
+
+        let (old, new) = fade.next_gains();
+        assert!(old > 0.99 && new < 0.05);
+
+        for _ in 0..98 {
+            let (old, new) = fade.next_gains();
+            assert!((old * old + new * new - 1.0).abs() < 1e-5);
+        }
+        assert!(!fade.is_done());
+
+        let (old, new) = fade.next_gains();
+        assert!(old.abs() < 1e-6 && new == 1.0);
+        assert!(fade.is_done());
+        assert_eq!(fade.next_gains(), (0.0, 1.0));
+    }
+}
@@ -0,0 +1,283 @@
+use std::fmt::Write;
+
+use cpal::{
+    traits::{DeviceTrait, HostTrait},
+    SupportedBufferSize, SupportedStreamConfigRange,
+};
+
+/// List the audio hosts and devices with what they support, to find a `--device` name
+#[derive(clap::Args, Debug)]
+pub(crate) struct DevicesArgs {
+    /// Print JSON instead of text, for scripts
+    #[arg(long)]
+    json: bool,
+}
+
+struct HostInfo {
+    name: &'static str,
+    /// Why the host or its devices couldn't be listed
+    error: Option<String>,
+    outputs: Vec<DeviceInfo>,
+    inputs: Vec<DeviceInfo>,
+}
+
+struct DeviceInfo {
+    name: String,
+    default: bool,
+    configs: Vec<ConfigInfo>,
+    /// Why the supported configs couldn't be listed
+    error: Option<String>,
+}
+
+struct ConfigInfo {
+    sample_format: String,
+    channels: u16,
+    sample_rates: (u32, u32),
+    /// Unknown for some hosts
+    buffer_sizes: Option<(u32, u32)>,
+}
+
+impl From<SupportedStreamConfigRange> for ConfigInfo {
+    fn from(config: SupportedStreamConfigRange) -> Self {
+        Self {
+            sample_format: config.sample_format().to_string(),
+            channels: config.channels(),
+            sample_rates: (config.min_sample_rate().0, config.max_sample_rate().0),
+            buffer_sizes: match *config.buffer_size() {
+                SupportedBufferSize::Range { min, max } => Some((min, max)),
+                SupportedBufferSize::Unknown => None,
+            },
+        }
+    }
+}
+
+pub(crate) fn devices(args: DevicesArgs) {
+    let hosts: Vec<_> = cpal::available_hosts()
+        .into_iter()
+        .map(|id| match cpal::host_from_id(id) {
+            Ok(host) => host_info(id.name(), &host),
+            Err(e) => HostInfo {
+                name: id.name(),
+                error: Some(e.to_string()),
+                outputs: vec![],
+                inputs: vec![],
+            },
+        })
+        .collect();
+
+    match args.json {
+        true => println!("{}", to_json(&hosts)),
+        false => print!("{}", to_text(&hosts)),
+    }
+}
+
+fn host_info(name: &'static str, host: &cpal::Host) -> HostInfo {
+    let mut errors = vec![];
+
+    let default_output = host.default_output_device().and_then(|d| d.name().ok());
+    let outputs = match host.output_devices() {
+        Ok(devices) => devices
+            .map(|device| {
+                device_info(&device, default_output.as_deref(), |d| {
+                    d.supported_output_configs().map(|c| c.collect())
+                })
+            })
+            .collect(),
+        Err(e) => {
+            errors.push(format!("list output devices: {e}"));
+            vec![]
+        }
+    };
+
+    let default_input = host.default_input_device().and_then(|d| d.name().ok());
+    let inputs = match host.input_devices() {
+        Ok(devices) => devices
+            .map(|device| {
+                device_info(&device, default_input.as_deref(), |d| {
+                    d.supported_input_configs().map(|c| c.collect())
+                })
+            })
+            .collect(),
+        Err(e) => {
+            errors.push(format!("list input devices: {e}"));
+            vec![]
+        }
+    };
+
+    HostInfo {
+        name,
+        error: (!errors.is_empty()).then(|| errors.join("; ")),
+        outputs,
+        inputs,
+    }
+}
+
+fn device_info(
+    device: &cpal::Device,
+    default: Option<&str>,
+    configs: impl Fn(
+        &cpal::Device,
+    ) -> Result<Vec<SupportedStreamConfigRange>, cpal::SupportedStreamConfigsError>,
+) -> DeviceInfo {
+    let name = device.name().unwrap_or_else(|e| format!("<unnamed: {e}>"));
+    let (configs, error) = match configs(device) {
+        Ok(configs) => (configs.into_iter().map(ConfigInfo::from).collect(), None),
+        Err(e) => (vec![], Some(e.to_string())),
+    };
+    DeviceInfo {
+        default: default == Some(name.as_str()),
+        name,
+        configs,
+        error,
+    }
+}
+
+fn to_text(hosts: &[HostInfo]) -> String {
+    let mut text = String::new();
+    for host in hosts {
+        let _ = writeln!(text, "{}", host.name);
+        if let Some(error) = &host.error {
+            let _ = writeln!(text, "  error: {error}");
+        }
+        let devices = [("output", &host.outputs), ("input", &host.inputs)];
+        for (kind, devices) in devices {
+            for device in devices {
+                let default = if device.default { " (default)" } else { "" };
+                let _ = writeln!(text, "  {kind} '{}'{default}", device.name);
+                if let Some(error) = &device.error {
+                    let _ = writeln!(text, "    error: {error}");
+                }
+                for config in &device.configs {
+                    let (min_rate, max_rate) = config.sample_rates;
+                    let _ = write!(
+                        text,
+                        "    {:<4} {} ch, {min_rate}-{max_rate} Hz",
+                        config.sample_format, config.channels
+                    );
+                    let _ = match config.buffer_sizes {
+                        Some((min, max)) => writeln!(text, ", buffer {min}-{max} frames"),
+                        None => writeln!(text, ", buffer size unknown"),
+                    };
+                }
+            }
+        }
+    }
+    text
+}
+
+fn to_json(hosts: &[HostInfo]) -> String {
+    let hosts: Vec<_> = hosts
+        .iter()
+        .map(|host| {
+            format!(
+                r#"{{"name":{},"error":{},"outputs":[{}],"inputs":[{}]}}"#,
+                json_string(host.name),
+                json_option(host.error.as_deref()),
+                devices_json(&host.outputs),
+                devices_json(&host.inputs),
+            )
+        })
+        .collect();
+    format!("[{}]", hosts.join(","))
+}
+
+fn devices_json(devices: &[DeviceInfo]) -> String {
+    let devices: Vec<_> = devices
+        .iter()
+        .map(|device| {
+            let configs: Vec<_> = device
+                .configs
+                .iter()
+                .map(|config| {
+                    let (min_buffer, max_buffer) = match config.buffer_sizes {
+                        Some((min, max)) => (min.to_string(), max.to_string()),
+                        None => (String::from("null"), String::from("null")),
+                    };
+                    format!(
+                        r#"{{"sample_format":{},"channels":{},"min_sample_rate":{},"max_sample_rate":{},"min_buffer_size":{min_buffer},"max_buffer_size":{max_buffer}}}"#,
+                        json_string(&config.sample_format),
+                        config.channels,
+                        config.sample_rates.0,
+                        config.sample_rates.1,
+                    )
+                })
+                .collect();
+            format!(
+                r#"{{"name":{},"default":{},"error":{},"configs":[{}]}}"#,
+                json_string(&device.name),
+                device.default,
+                json_option(device.error.as_deref()),
+                configs.join(","),
+            )
+        })
+        .collect();
+    devices.join(",")
+}
+
+fn json_option(value: Option<&str>) -> String {
+    value.map_or(String::from("null"), json_string)
+}
+
+fn json_string(value: &str) -> String {
+    let mut json = String::with_capacity(value.len() + 2);
+    json.push('"');
+    for c in value.chars() {
+        match c {
+            '"' => json.push_str("\\\""),
+            '\\' => json.push_str("\\\\"),
+            '\n' => json.push_str("\\n"),
+            '\r' => json.push_str("\\r"),
+            '\t' => json.push_str("\\t"),
+            c if c.is_control() => {
+                let _ = write!(json, "\\u{:04x}", c as u32);
+            }
+            c => json.push(c),
+        }
+    }
+    json.push('"');
+    json
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{to_json, to_text, ConfigInfo, DeviceInfo, HostInfo};
+
+    #[test]
+    fn list_as_text_and_json() {
+        let hosts = [HostInfo {
+            name: "ALSA",
+            error: None,
+            outputs: vec![DeviceInfo {
+                name: String::from(r#"USB "out" \ 1"#),
+                default: true,
+                configs: vec![ConfigInfo {
+                    sample_format: String::from("i16"),
+                    channels: 2,
+                    sample_rates: (44100, 48000),
+                    buffer_sizes: Some((64, 4096)),
+                }],
+                error: None,
+            }],
+            inputs: vec![DeviceInfo {
+                name: String::from("mic"),
+                default: false,
+                configs: vec![],
+                error: Some(String::from("busy")),
+            }],
+        }];
+
+        assert_eq!(
+            to_text(&hosts),
+            r#"ALSA
+  output 'USB "out" \ 1' (default)
+    i16  2 ch, 44100-48000 Hz, buffer 64-4096 frames
+  input 'mic'
+    error: busy
+"#
+        );
+        assert_eq!(
+            to_json(&hosts),
+            r#"[{"name":"ALSA","error":null,"outputs":[{"name":"USB \"out\" \\ 1","default":true,"error":null,"configs":[{"sample_format":"i16","channels":2,"min_sample_rate":44100,"max_sample_rate":48000,"min_buffer_size":64,"max_buffer_size":4096}]}],"inputs":[{"name":"mic","default":false,"error":"busy","configs":[]}]}]"#
+        );
+    }
+}
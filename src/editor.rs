@@ -0,0 +1,277 @@
+use crossterm::event::{KeyCode, KeyEvent, KeyModifiers};
+use tracing::warn;
+
+/// Spaces inserted by the tab key
+const TAB: &str = "    ";
+
+/// What the TUI should do after a key was given to the editor
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum EditorAction {
+    None,
+    /// Save the buffer and play it
+    Evaluate,
+    /// Give the keys back to the TUI
+    Leave,
+}
+
+/// Text buffer of the watched file, edited from the TUI
+pub(crate) struct Editor {
+    lines: Vec<String>,
+    /// Cursor line and column, in chars
+    row: usize,
+    col: usize,
+    /// First line and column shown
+    scroll: (usize, usize),
+    /// Edited since the last save or reload
+    modified: bool,
+}
+
+impl Editor {
+    pub fn new(content: &str) -> Self {
+        Self {
+            lines: content.split('\n').map(String::from).collect(),
+            row: 0,
+            col: 0,
+            scroll: (0, 0),
+            modified: false,
+        }
+    }
+
+    pub fn text(&self) -> String {
+        self.lines.join("\n")
+    }
+
+    pub fn lines(&self) -> &[String] {
+        &self.lines
+    }
+
+    pub fn is_modified(&self) -> bool {
+        self.modified
+    }
+
+    /// The buffer was written to the file
+    pub fn mark_saved(&mut self) {
+        self.modified = false;
+    }
+
+    /// Show the file changed by another editor, unless the buffer has unsaved edits
+    pub fn reload(&mut self, content: &str) {
+        if content == self.text() {
+            self.modified = false;
+            return;
+        }
+        if self.modified {
+            warn!("file changed on disk, keeping the unsaved edits");
+            return;
+        }
+
+        self.lines = content.split('\n').map(String::from).collect();
+        self.row = self.row.min(self.lines.len() - 1);
+        self.col = self.col.min(self.line_len());
+    }
+
+    pub fn handle_key(&mut self, key: KeyEvent) -> EditorAction {
+        let control = key.modifiers.contains(KeyModifiers::CONTROL);
+        let alt = key.modifiers.contains(KeyModifiers::ALT);
+        match key.code {
+            // most terminals can't tell ctrl-enter from enter, hence the alternatives
+            KeyCode::Enter if control || alt => return EditorAction::Evaluate,
+            KeyCode::Char('s') if control => return EditorAction::Evaluate,
+            KeyCode::Esc => return EditorAction::Leave,
+            KeyCode::Char(_) if control || alt => {}
+            KeyCode::Char(c) => self.insert(&c.to_string()),
+            KeyCode::Tab => self.insert(TAB),
+            KeyCode::Enter => self.split_line(),
+            KeyCode::Backspace => self.backspace(),
+            KeyCode::Delete => self.delete(),
+            KeyCode::Left if self.col > 0 => self.col -= 1,
+            KeyCode::Left if self.row > 0 => {
+                self.row -= 1;
+                self.col = self.line_len();
+            }
+            KeyCode::Right if self.col < self.line_len() => self.col += 1,
+            KeyCode::Right if self.row + 1 < self.lines.len() => {
+                self.row += 1;
+                self.col = 0;
+            }
+            KeyCode::Up => self.move_to_row(self.row.saturating_sub(1)),
+            KeyCode::Down => self.move_to_row(self.row + 1),
+            KeyCode::PageUp => self.move_to_row(self.row.saturating_sub(10)),
+            KeyCode::PageDown => self.move_to_row(self.row + 10),
+            KeyCode::Home => self.col = 0,
+            KeyCode::End => self.col = self.line_len(),
+            _ => {}
+        }
+        EditorAction::None
+    }
+
+    /// Cursor position within a view of the given size, scrolling to keep it visible
+    pub fn scroll_to_cursor(&mut self, width: usize, height: usize) -> (usize, usize) {
+        let (top, left) = &mut self.scroll;
+        if self.row < *top {
+            *top = self.row;
+        } else if self.row >= *top + height {
+            *top = self.row + 1 - height;
+        }
+        if self.col < *left {
+            *left = self.col;
+        } else if self.col >= *left + width {
+            *left = self.col + 1 - width;
+        }
+        (self.row - *top, self.col - *left)
+    }
+
+    /// First line and column shown, as of the last scroll
+    pub fn scroll(&self) -> (usize, usize) {
+        self.scroll
+    }
+
+    fn line_len(&self) -> usize {
+        self.lines[self.row].chars().count()
+    }
+
+    fn byte_index(&self) -> usize {
+        let line = &self.lines[self.row];
+        line.char_indices()
+            .nth(self.col)
+            .map_or(line.len(), |(i, _)| i)
+    }
+
+    fn move_to_row(&mut self, row: usize) {
+        self.row = row.min(self.lines.len() - 1);
+        self.col = self.col.min(self.line_len());
+    }
+
+    fn insert(&mut self, text: &str) {
+        let at = self.byte_index();
+        self.lines[self.row].insert_str(at, text);
+        self.col += text.chars().count();
+        self.modified = true;
+    }
+
+    /// Break the line at the cursor, keeping its indentation
+    fn split_line(&mut self) {
+        let at = self.byte_index();
+        let rest = self.lines[self.row].split_off(at);
+        let indent: String = self.lines[self.row]
+            .chars()
+            .take_while(|c| c.is_whitespace())
+            .collect();
+        self.col = indent.chars().count();
+        self.row += 1;
+        self.lines.insert(self.row, indent + rest.as_str());
+        self.modified = true;
+    }
+
+    fn backspace(&mut self) {
+        if self.col > 0 {
+            self.col -= 1;
+            let at = self.byte_index();
+            self.lines[self.row].remove(at);
+        } else if self.row > 0 {
+            let line = self.lines.remove(self.row);
+            self.row -= 1;
+            self.col = self.line_len();
+            self.lines[self.row].push_str(&line);
+        } else {
+            return;
+        }
+        self.modified = true;
+    }
+
+    fn delete(&mut self) {
+        if self.col < self.line_len() {
+            let at = self.byte_index();
+            self.lines[self.row].remove(at);
+        } else if self.row + 1 < self.lines.len() {
+            let line = self.lines.remove(self.row + 1);
+            self.lines[self.row].push_str(&line);
+        } else {
+            return;
+        }
+        self.modified = true;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Editor, EditorAction};
+
+    use crossterm::event::{KeyCode, KeyEvent, KeyModifiers};
+
+    fn press(editor: &mut Editor, code: KeyCode) -> EditorAction {
+        editor.handle_key(KeyEvent::new(code, KeyModifiers::NONE))
+    }
+
+    fn type_text(editor: &mut Editor, text: &str) {
+        for c in text.chars() {
+            match c {
+                '\n' => press(editor, KeyCode::Enter),
+                c => press(editor, KeyCode::Char(c)),
+            };
+        }
+    }
+
+    #[test]
+    fn edit_lines() {
+        let mut editor = Editor::new("o: sin 440\n");
+        press(&mut editor, KeyCode::End);
+        type_text(&mut editor, " >> mul 0.5");
+        press(&mut editor, KeyCode::Down);
+        type_text(&mut editor, "  ~a: saw 110\nécho");
+        assert_eq!(
+            editor.text(),
+            "o: sin 440 >> mul 0.5\n  ~a: saw 110\n  écho"
+        );
+        assert!(editor.is_modified());
+
+        press(&mut editor, KeyCode::Left);
+        press(&mut editor, KeyCode::Backspace);
+        press(&mut editor, KeyCode::Delete);
+        assert_eq!(editor.text(), "o: sin 440 >> mul 0.5\n  ~a: saw 110\n  éc");
+
+        press(&mut editor, KeyCode::Home);
+        press(&mut editor, KeyCode::Backspace);
+        assert_eq!(editor.text(), "o: sin 440 >> mul 0.5\n  ~a: saw 110  éc");
+        press(&mut editor, KeyCode::Up);
+        press(&mut editor, KeyCode::End);
+        press(&mut editor, KeyCode::Delete);
+        assert_eq!(editor.text(), "o: sin 440 >> mul 0.5  ~a: saw 110  éc");
+    }
+
+    #[test]
+    fn evaluate_and_leave() {
+        let mut editor = Editor::new("");
+        let ctrl_enter = KeyEvent::new(KeyCode::Enter, KeyModifiers::CONTROL);
+        let ctrl_s = KeyEvent::new(KeyCode::Char('s'), KeyModifiers::CONTROL);
+        assert_eq!(editor.handle_key(ctrl_enter), EditorAction::Evaluate);
+        assert_eq!(editor.handle_key(ctrl_s), EditorAction::Evaluate);
+        assert_eq!(press(&mut editor, KeyCode::Esc), EditorAction::Leave);
+        assert_eq!(editor.text(), "");
+    }
+
+    #[test]
+    fn reload_unless_modified() {
+        let mut editor = Editor::new("o: sin 440");
+        editor.reload("o: saw 110");
+        assert_eq!(editor.text(), "o: saw 110");
+
+        type_text(&mut editor, "~");
+        editor.reload("o: squ 220");
+        assert_eq!(editor.text(), "~o: saw 110");
+
+        // saved by the editor, then picked up by the watcher
+        editor.reload("~o: saw 110");
+        assert!(!editor.is_modified());
+    }
+
+    #[test]
+    fn scroll_to_the_cursor() {
+        let mut editor = Editor::new(&"line\n".repeat(20));
+        assert_eq!(editor.scroll_to_cursor(3, 5), (0, 0));
+        press(&mut editor, KeyCode::PageDown);
+        press(&mut editor, KeyCode::End);
+        assert_eq!(editor.scroll_to_cursor(3, 5), (4, 2));
+        assert_eq!(editor.scroll(), (6, 2));
+    }
+}
@@ -0,0 +1,150 @@
+/// What a piece of Glicol code is, to pick its colour
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum Token {
+    Plain,
+    /// `~name:` or `out:` starting a chain
+    Chain,
+    /// `~name` used as a parameter
+    Reference,
+    /// `>>` between nodes
+    Operator,
+    /// First word of a node, like `sin` or `seq`
+    Node,
+    Number,
+    Comment,
+}
+
+/// Split a line of code into highlighted pieces, covering the whole line
+pub(crate) fn highlight_line(line: &str) -> Vec<(Token, &str)> {
+    let (code, comment) = match line.find("//") {
+        Some(start) => line.split_at(start),
+        None => (line, ""),
+    };
+
+    let mut tokens = vec![];
+    // a node name is expected after a chain name or `>>`
+    let mut expect_node = false;
+    let mut rest = code;
+    while !rest.is_empty() {
+        let end = match rest.find(|c: char| !c.is_whitespace()) {
+            Some(0) => rest.find(char::is_whitespace).unwrap_or(rest.len()),
+            Some(space) => space,
+            None => rest.len(),
+        };
+        let (piece, after) = rest.split_at(end);
+        rest = after;
+
+        let token = if piece.trim().is_empty() {
+            Token::Plain
+        } else if piece.ends_with(':') && tokens.iter().all(|(t, _)| *t == Token::Plain) {
+            expect_node = true;
+            Token::Chain
+        } else if piece == ">>" {
+            expect_node = true;
+            Token::Operator
+        } else if std::mem::take(&mut expect_node) {
+            Token::Node
+        } else if piece.starts_with('~') {
+            Token::Reference
+        } else if is_number(piece) {
+            Token::Number
+        } else {
+            Token::Plain
+        };
+        tokens.push((token, piece));
+    }
+
+    if !comment.is_empty() {
+        tokens.push((Token::Comment, comment));
+    }
+    tokens
+}
+
+/// Numbers and sequences of them, like `0.5`, `-1` or `60_33`
+fn is_number(piece: &str) -> bool {
+    piece.chars().any(|c| c.is_ascii_digit())
+        && piece
+            .chars()
+            .all(|c| c.is_ascii_digit() || matches!(c, '.' | '-' | '_'))
+}
+
+/// Which lines of `new` aren't in `old`, by longest common subsequence
+pub(crate) fn changed_lines(old: &str, new: &str) -> Vec<bool> {
+    let old: Vec<_> = old.lines().collect();
+    let new: Vec<_> = new.lines().collect();
+
+    // common[i][j] is the length of the common subsequence of old[i..] and new[j..]
+    let mut common = vec![vec![0; new.len() + 1]; old.len() + 1];
+    for i in (0..old.len()).rev() {
+        for j in (0..new.len()).rev() {
+            common[i][j] = match old[i] == new[j] {
+                true => common[i + 1][j + 1] + 1,
+                false => common[i + 1][j].max(common[i][j + 1]),
+            };
+        }
+    }
+
+    let mut changed = vec![true; new.len()];
+    let (mut i, mut j) = (0, 0);
+    while i < old.len() && j < new.len() {
+        if old[i] == new[j] {
+            changed[j] = false;
+            i += 1;
+            j += 1;
+        } else if common[i + 1][j] >= common[i][j + 1] {
+            i += 1;
+        } else {
+            j += 1;
+        }
+    }
+    changed
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{changed_lines, highlight_line, Token};
+
+    #[test]
+    fn highlight_chains() {
+        assert_eq!(
+            highlight_line("~t1: seq 60_33 >> mul ~amp // kick"),
+            [
+                (Token::Chain, "~t1:"),
+                (Token::Plain, " "),
+                (Token::Node, "seq"),
+                (Token::Plain, " "),
+                (Token::Number, "60_33"),
+                (Token::Plain, " "),
+                (Token::Operator, ">>"),
+                (Token::Plain, " "),
+                (Token::Node, "mul"),
+                (Token::Plain, " "),
+                (Token::Reference, "~amp"),
+                (Token::Plain, " "),
+                (Token::Comment, "// kick"),
+            ]
+        );
+        assert_eq!(
+            highlight_line(">> lpf 1000.0 1.0"),
+            [
+                (Token::Operator, ">>"),
+                (Token::Plain, " "),
+                (Token::Node, "lpf"),
+                (Token::Plain, " "),
+                (Token::Number, "1000.0"),
+                (Token::Plain, " "),
+                (Token::Number, "1.0"),
+            ]
+        );
+        assert_eq!(highlight_line(""), []);
+    }
+
+    #[test]
+    fn find_changed_lines() {
+        let old = "~a: sin 440\n~b: saw 110\nout: mix ~a ~b";
+        let new = "~a: sin 440\n~c: squ 55\n~b: saw 110\nout: mix ~a ~b ~c";
+        assert_eq!(changed_lines(old, new), [false, true, false, true]);
+        assert_eq!(changed_lines("", "o: sin 440"), [true]);
+        assert_eq!(changed_lines(old, ""), [] as [bool; 0]);
+    }
+}
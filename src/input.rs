@@ -0,0 +1,286 @@
+use std::sync::mpsc;
+
+use cpal::{
+    traits::{DeviceTrait, StreamTrait},
+    FromSample, SizedSample, StreamError,
+};
+use ringbuf::{HeapConsumer, HeapProducer, HeapRb};
+
+use crate::BLOCK_SIZE;
+
+/// Seconds of stereo audio the bridge can hold
+const BUFFER_SECONDS: usize = 1;
+
+/// Largest deviation from the nominal resampling ratio used to absorb clock drift
+const MAX_DRIFT: f64 = 0.005;
+
+/// Open the input stream, pushing its audio into the returned bridge
+///
+/// The bridge is meant to be read from the output callback at `output_sr`, the stream errors are
+/// sent to `errors`
+pub(crate) fn run_input(
+    device: &cpal::Device,
+    config: &cpal::SupportedStreamConfig,
+    output_sr: u32,
+    errors: &mpsc::Sender<StreamError>,
+) -> anyhow::Result<(cpal::Stream, InputBridge)> {
+    let (capture, bridge) = bridge(
+        config.channels() as usize,
+        config.sample_rate().0,
+        output_sr,
+    );
+    let format = config.sample_format();
+    let config = config.clone().into();
+
+    let stream = match format {
+        cpal::SampleFormat::I8 => build_input_stream::<i8>(device, &config, capture, errors),
+        cpal::SampleFormat::I16 => build_input_stream::<i16>(device, &config, capture, errors),
+        cpal::SampleFormat::I32 => build_input_stream::<i32>(device, &config, capture, errors),
+        cpal::SampleFormat::I64 => build_input_stream::<i64>(device, &config, capture, errors),
+        cpal::SampleFormat::U8 => build_input_stream::<u8>(device, &config, capture, errors),
+        cpal::SampleFormat::U16 => build_input_stream::<u16>(device, &config, capture, errors),
+        cpal::SampleFormat::U32 => build_input_stream::<u32>(device, &config, capture, errors),
+        cpal::SampleFormat::U64 => build_input_stream::<u64>(device, &config, capture, errors),
+        cpal::SampleFormat::F32 => build_input_stream::<f32>(device, &config, capture, errors),
+        cpal::SampleFormat::F64 => build_input_stream::<f64>(device, &config, capture, errors),
+        sample_format => anyhow::bail!("Unsupported input sample format '{sample_format}'"),
+    }?;
+    stream.play()?;
+
+    Ok((stream, bridge))
+}
+
+fn build_input_stream<T>(
+    device: &cpal::Device,
+    config: &cpal::StreamConfig,
+    mut capture: InputCapture,
+    errors: &mpsc::Sender<StreamError>,
+) -> anyhow::Result<cpal::Stream>
+where
+    T: SizedSample,
+    f32: FromSample<T>,
+{
+    let errors = errors.clone();
+    let stream = device.build_input_stream(
+        config,
+        move |data: &[T], _: &cpal::InputCallbackInfo| capture.push(data),
+        move |err| {
+            let _ = errors.send(err);
+        },
+        None,
+    )?;
+
+    Ok(stream)
+}
+
+/// Pick an input config running at the output's sample rate if the device allows it
+pub(crate) fn input_config(
+    device: &cpal::Device,
+    sample_rate: cpal::SampleRate,
+) -> anyhow::Result<cpal::SupportedStreamConfig> {
+    let matching = device.supported_input_configs()?.find(|range| {
+        range.channels() <= 2
+            && range.min_sample_rate() <= sample_rate
+            && sample_rate <= range.max_sample_rate()
+    });
+
+    Ok(match matching {
+        Some(range) => range.with_sample_rate(sample_rate),
+        None => device.default_input_config()?,
+    })
+}
+
+/// Create both ends of a bridge converting `channels` at `input_sr` to stereo at `output_sr`
+pub(crate) fn bridge(
+    channels: usize,
+    input_sr: u32,
+    output_sr: u32,
+) -> (InputCapture, InputBridge) {
+    let (producer, consumer) = HeapRb::new(BUFFER_SECONDS * input_sr as usize * 2).split();
+
+    // enough to absorb the bursts of both callbacks
+    let target_fill = (input_sr as usize / 50).max(BLOCK_SIZE * 4);
+
+    let capture = InputCapture { producer, channels };
+    let bridge = InputBridge {
+        consumer,
+        ratio: input_sr as f64 / output_sr as f64,
+        target_fill,
+        average_fill: target_fill as f64,
+        priming: true,
+        position: 1.0,
+        previous: [0.0; 2],
+        next: [0.0; 2],
+    };
+
+    (capture, bridge)
+}
+
+/// Input callback side of the bridge
+pub(crate) struct InputCapture {
+    producer: HeapProducer<f32>,
+    channels: usize,
+}
+
+impl InputCapture {
+    /// Queue interleaved input samples as stereo frames, mono is copied to both sides
+    ///
+    /// Frames that don't fit are dropped, the bridge then catches up by resampling
+    pub fn push<T>(&mut self, data: &[T])
+    where
+        T: SizedSample,
+        f32: FromSample<T>,
+    {
+        for frame in data.chunks_exact(self.channels) {
+            let left = frame[0].to_sample::<f32>();
+            let right = frame.get(1).map_or(left, |s| s.to_sample::<f32>());
+            self.producer.push_slice(&[left, right]);
+        }
+    }
+}
+
+/// Output callback side of the bridge
+///
+/// The input and output devices don't share a clock, so the bridge resamples the captured
+/// audio, slightly adjusting the ratio to keep the buffered amount around its target.
+pub(crate) struct InputBridge {
+    consumer: HeapConsumer<f32>,
+    /// Input frames consumed per output frame, without drift correction
+    ratio: f64,
+    target_fill: usize,
+    /// Smoothed buffered frames, as callbacks deliver audio in bursts
+    average_fill: f64,
+    /// Waiting for the buffer to reach its target before playing
+    priming: bool,
+    /// Fractional position between `previous` and `next`
+    position: f64,
+    previous: [f32; 2],
+    next: [f32; 2],
+}
+
+impl InputBridge {
+    /// Frames currently buffered
+    fn fill(&self) -> usize {
+        self.consumer.len() / 2
+    }
+
+    /// Drop everything buffered, used while the output is paused
+    pub fn clear(&mut self) {
+        self.consumer.clear();
+        self.priming = true;
+    }
+
+    /// Fill a stereo block with resampled input, silence when not enough was captured
+    pub fn read_block(&mut self, block: &mut [[f32; BLOCK_SIZE]; 2]) {
+        let fill = self.fill();
+        if self.priming {
+            if fill < self.target_fill {
+                block.iter_mut().for_each(|chan| chan.fill(0.0));
+                return;
+            }
+            self.priming = false;
+            self.average_fill = fill as f64;
+        }
+
+        self.average_fill += (fill as f64 - self.average_fill) * 0.01;
+        let drift = (self.average_fill - self.target_fill as f64) / self.target_fill as f64;
+        let step = self.ratio * (1.0 + (drift * 0.01).clamp(-MAX_DRIFT, MAX_DRIFT));
+
+        for i in 0..BLOCK_SIZE {
+            while self.position >= 1.0 {
+                let mut frame = [0.0; 2];
+                if self.consumer.pop_slice(&mut frame) < 2 {
+                    // underrun, wait for the buffer to fill up again
+                    self.priming = true;
+                    for chan in block.iter_mut() {
+                        chan[i..].fill(0.0);
+                    }
+                    return;
+                }
+                self.previous = self.next;
+                self.next = frame;
+                self.position -= 1.0;
+            }
+
+            let t = self.position as f32;
+            for (chan, out) in block.iter_mut().enumerate() {
+                out[i] = self.previous[chan] + (self.next[chan] - self.previous[chan]) * t;
+            }
+            self.position += step;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::bridge;
+
+    use crate::BLOCK_SIZE;
+
+    #[test]
+    fn wait_for_target_fill() {
+        let (mut capture, mut bridge) = bridge(2, 48000, 48000);
+        let mut block = [[1.0; BLOCK_SIZE]; 2];
+
+        capture.push(&[0.5f32; BLOCK_SIZE * 2]);
+        bridge.read_block(&mut block);
+        assert_eq!(block, [[0.0; BLOCK_SIZE]; 2]);
+
+        capture.push(&vec![0.5f32; 48000 / 25 * 2]);
+        bridge.read_block(&mut block);
+        assert!(block[0][1..].iter().all(|s| *s == 0.5));
+    }
+
+    #[test]
+    fn mono_goes_to_both_sides() {
+        let (mut capture, mut bridge) = bridge(1, 48000, 48000);
+        let mut block = [[0.0; BLOCK_SIZE]; 2];
+
+        capture.push(&vec![-0.25f32; 48000 / 25]);
+        bridge.read_block(&mut block);
+        assert_eq!(block[0][BLOCK_SIZE - 1], -0.25);
+        assert_eq!(block[1][BLOCK_SIZE - 1], -0.25);
+    }
+
+    #[test]
+    fn resample_to_output_rate() {
+        let (mut capture, mut bridge) = bridge(1, 24000, 48000);
+        let mut block = [[0.0; BLOCK_SIZE]; 2];
+
+        let ramp = (0..2000).map(|i| i as f32).collect::<Vec<_>>();
+        capture.push(&ramp);
+        bridge.read_block(&mut block);
+        bridge.read_block(&mut block);
+
+        // a linear ramp stays linear, each output frame being half an input frame later
+        let slope = block[0][BLOCK_SIZE - 1] - block[0][BLOCK_SIZE - 2];
+        assert!((slope - 0.5).abs() < 0.01, "slope {slope}");
+    }
+
+    #[test]
+    fn keep_fill_around_target() {
+        let (mut capture, mut bridge) = bridge(2, 48000, 48000);
+        let mut block = [[0.0; BLOCK_SIZE]; 2];
+
+        // the input device runs slightly faster than the output one
+        let mut produced = 0.0;
+        for _ in 0..5000 {
+            produced += BLOCK_SIZE as f64 * 1.001;
+            let frames = produced as usize;
+            produced -= frames as f64;
+            capture.push(&vec![0.1f32; frames * 2]);
+            bridge.read_block(&mut block);
+        }
+
+        assert!(
+            bridge.fill() < bridge.target_fill * 2,
+            "fill {}",
+            bridge.fill()
+        );
+        assert!(
+            bridge.fill() > bridge.target_fill / 2,
+            "fill {}",
+            bridge.fill()
+        );
+    }
+}
@@ -1,48 +1,130 @@
+mod clock;
+mod control;
+mod crossfade;
+mod devices;
+mod editor;
+mod highlight;
+mod input;
+mod master;
+mod meter;
+mod midi;
+mod osc;
+mod output;
+mod quantize;
 mod recent_lines;
+mod record;
+mod recovery;
+mod render;
+mod routing;
 mod samples;
+mod scope;
+mod spectrum;
+mod tempo;
+mod timing;
+mod tracks;
 mod tui;
+mod updater;
 mod watcher;
+mod wav;
 
 use tui::*;
 use watcher::watch_path;
 
 use anyhow::{Context, Result};
-use clap::{CommandFactory, Parser};
+use clap::{CommandFactory, Parser, Subcommand};
+use clock::{ClockFollower, ClockPosition};
+use control::{Constants, Control, ControlReceiver};
 use cpal::traits::{DeviceTrait, HostTrait, StreamTrait};
 use cpal::{FromSample, SizedSample, SupportedStreamConfig};
+use crossfade::Crossfade;
+use editor::Editor;
 use glicol::Engine;
+use glicol_synth::Buffer;
+use input::InputBridge;
+use master::{MasterBus, MasterState};
+use meter::{BlockLevels, Levels};
+use quantize::{Quantize, Transport};
+use record::{RecordTap, Recorder};
+use recovery::DeviceStatus;
+use routing::Routing;
+use scope::ScopeTap;
 use std::error::Error;
-use std::path::Path;
-use std::sync::atomic::{AtomicBool, AtomicPtr, AtomicU32, AtomicUsize, Ordering};
-use std::sync::mpsc::TryRecvError;
-use std::sync::{self, Arc};
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use std::sync::{mpsc, Arc, Mutex};
 use std::time::{Duration, Instant}; // , SystemTime, UNIX_EPOCH
 use std::{io, thread}; // use std::time::{Instant};
+use timing::CallbackStats;
 use tracing::error;
 use tracing_subscriber::fmt::{format::FmtSpan, time::ChronoLocal};
+use updater::{EngineHandoff, PreparedEngine, Transition};
 
-pub const RB_SIZE: usize = 200;
 pub const BLOCK_SIZE: usize = 128;
 
 /// Glicol cli tool. This tool will watch the changes in a .glicol file.
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about = None)]
+#[command(args_conflicts_with_subcommands = true)]
 struct Args {
+    #[command(subcommand)]
+    command: Option<Command>,
+
     /// path to the .glicol file
     #[arg(index = 1)]
-    file: String,
+    file: Option<String>,
 
     // Show a scope or not
     // #[arg(short, long)]
     // scope: bool,
     /// Set beats per minute (BPM)
-    #[arg(short, long, default_value_t = 120.0)]
+    #[arg(short, long, default_value_t = 120.0, allow_negative_numbers = true, value_parser = tempo::parse_bpm)]
     bpm: f32,
 
+    /// When code updates start playing, overridden by a `// @quantize <none|beat|bar>` line
+    #[arg(short, long, value_enum, default_value_t = Quantize::Bar)]
+    quantize: Quantize,
+
+    /// Milliseconds during which the old and new code play together on updates, 0 to cut
+    #[arg(short, long, default_value_t = 50, value_parser = clap::value_parser!(u32).range(0..=1000))]
+    crossfade: u32,
+
+    /// Number of frames shown by the scope
+    #[arg(long, default_value_t = 200, value_parser = clap::value_parser!(u32).range(16..=48000))]
+    scope_length: u32,
+
+    /// Level the output never exceeds, in dBFS
+    #[arg(long, default_value_t = -1.0, allow_negative_numbers = true, value_parser = master::parse_ceiling)]
+    ceiling: f32,
+
+    /// Number of frames analyzed by the spectrum views, a power of two
+    #[arg(long, default_value_t = 2048, value_parser = spectrum::parse_fft_size)]
+    fft_size: usize,
+
     /// The audio device to use
     #[arg(short, long, default_value_t = String::from("default"))]
     device: String,
 
+    /// Sample rate of the output, the device's default otherwise
+    #[arg(long)]
+    sample_rate: Option<u32>,
+
+    /// Frames per output buffer, smaller for less latency, larger against dropouts
+    #[arg(long, value_parser = clap::value_parser!(u32).range(16..=16384))]
+    buffer_size: Option<u32>,
+
+    /// Output channels, mixed down to mono for 1 and silent beyond the first two
+    #[arg(long, value_parser = clap::value_parser!(u16).range(1..=64))]
+    channels: Option<u16>,
+
+    /// Play a source on an output channel, e.g. 3=rear.left, instead of the mix on 1 and 2
+    #[arg(long, value_name = "CHANNEL=SOURCE", value_parser = routing::parse_route)]
+    route: Vec<(u16, routing::Source)>,
+
+    /// The audio input device feeding `~input`, "default" for the default one
+    #[arg(short, long)]
+    input_device: Option<String>,
+
     /// Use the JACK host
     #[cfg(all(
         any(
@@ -60,6 +142,36 @@ struct Args {
     /// Disable the TUI
     #[arg(short = 'H', long, action = clap::ArgAction::SetTrue)]
     headless: bool,
+
+    /// Log audio callback timings and dropouts every this many seconds, with --headless
+    #[arg(long, value_name = "SECONDS", requires = "headless", value_parser = clap::value_parser!(u64).range(1..))]
+    stats: Option<u64>,
+
+    /// Record the output to a WAV file, later takes get a numbered suffix
+    #[arg(short, long)]
+    record: Option<PathBuf>,
+
+    /// Listen for OSC messages on this UDP address, e.g. 127.0.0.1:9000
+    #[arg(long)]
+    osc: Option<SocketAddr>,
+
+    /// Open a MIDI input port driving the constants listed in this mapping file
+    #[arg(long)]
+    midi_map: Option<PathBuf>,
+
+    /// Send MIDI clock, start and stop from an output port
+    #[arg(long, action = clap::ArgAction::SetTrue)]
+    midi_clock_out: bool,
+
+    /// Follow the tempo, start and stop of the MIDI clock received on the input port
+    #[arg(long, action = clap::ArgAction::SetTrue)]
+    midi_clock_in: bool,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    Render(render::RenderArgs),
+    Devices(devices::DevicesArgs),
 }
 
 #[allow(unused_must_use)]
@@ -71,7 +183,27 @@ fn main() -> Result<(), Box<dyn Error>> {
         return Ok(());
     }
     let args = Args::parse();
-    let path = args.file;
+
+    match args.command {
+        Some(Command::Render(render_args)) => {
+            tracing_subscriber::fmt()
+                .with_timer(ChronoLocal::new(String::from("%H:%M:%S%.3f")))
+                .init();
+            render::render(render_args)?;
+            return Ok(());
+        }
+        Some(Command::Devices(devices_args)) => {
+            devices::devices(devices_args);
+            return Ok(());
+        }
+        None => (),
+    }
+
+    let Some(path) = args.file else {
+        Args::command().print_help()?;
+        println!();
+        return Ok(());
+    };
     // let scope = args.scope;
     let device = args.device;
     let bpm = args.bpm;
@@ -79,25 +211,6 @@ fn main() -> Result<(), Box<dyn Error>> {
     // keep logs
     const RECENT_LINES_COUNT: usize = 100;
 
-    // let mut ringbuf_l = [0.0; RB_SIZE];
-    // let mut ringbuf_r = [0.0; RB_SIZE];
-    // let index = Arc::new(AtomicUsize::new(0));
-    // let index_clone = Arc::clone(&index);
-    // let ptr_rb_left = Arc::new(AtomicPtr::<f32>::new(ringbuf_l.as_mut_ptr()));
-    // let ptr_rb_right = Arc::new(AtomicPtr::<f32>::new(ringbuf_r.as_mut_ptr()));
-    // let ptr_rb_left_clone = Arc::clone(&ptr_rb_left);
-    // let ptr_rb_right_clone = Arc::clone(&ptr_rb_right);
-
-    let mut samples_l = [0.0; RB_SIZE];
-    let mut samples_r = [0.0; RB_SIZE];
-
-    let sample_data = Arc::new(SampleData {
-        left_ptr: AtomicPtr::<f32>::new(samples_l.as_mut_ptr()),
-        right_ptr: AtomicPtr::<f32>::new(samples_r.as_mut_ptr()),
-        index: AtomicUsize::new(0),
-        capacity: AtomicU32::new(0),
-        paused: AtomicBool::new(false),
-    });
     // let is_stopping = Arc::new(AtomicBool::new(false));
     // let is_stopping_clone = Arc::clone(&is_stopping);
 
@@ -133,122 +246,223 @@ fn main() -> Result<(), Box<dyn Error>> {
     ))]
     let host = cpal::default_host();
 
-    let device = if device == "default" {
-        host.default_output_device()
-            .expect("No default output device found")
-    } else {
-        let Some(device) = host
-            .output_devices()?
-            .find(|x| x.name().map_or(false, |y| y == device))
-        else {
-            eprintln!("Couldn't find output device '{device}'. Available options are:");
-            for dev_name in host.output_devices()?.filter_map(|d| d.name().ok()) {
-                eprintln!("  {dev_name}");
-            }
-            std::process::exit(1);
-        };
+    let device = find_device(&host, &device, DeviceKind::Output)?;
 
-        device
-    };
+    let sample_data = Arc::new(SampleData {
+        bpm: AtomicU32::new(bpm.to_bits()),
+        capacity: AtomicU32::new(0),
+        paused: AtomicBool::new(false),
+        clock: ClockPosition::new(),
+        levels: Levels::new(),
+        master: MasterState::new(args.ceiling),
+        device: DeviceStatus::new(&device.name()?),
+        timing: CallbackStats::new(),
+    });
 
     // println!("Output device: {}", device.name()?);
-    let config = device.default_output_config()?;
-
-    // limit to stereo
-    let config = SupportedStreamConfig::new(
-        2,
-        config.sample_rate(),
-        config.buffer_size().clone(),
-        config.sample_format(),
-    );
-    // println!("Default output config: {:?}", config);
+    let output_options = output::OutputOptions {
+        sample_rate: args.sample_rate,
+        buffer_size: args.buffer_size,
+        channels: args.channels,
+    };
+    let (config, sample_format) =
+        output::output_config(&device, output_options).context("pick output config")?;
 
-    let info: String = format!("{:?} {:?}", device.name()?.clone(), config.clone());
+    let routing = Routing::new(&args.route, config.channels).context("route output")?;
+
+    let info: String = format!("{:?} {sample_format}", config.clone());
+
+    let input = match args.input_device {
+        Some(name) => {
+            let input_device = find_device(&host, &name, DeviceKind::Input)?;
+            let input_config = input::input_config(&input_device, config.sample_rate)
+                .context("get input config")?;
+            Some((input_device, input_config))
+        }
+        None => None,
+    };
+
+    let midi_mapping = match &args.midi_map {
+        Some(path) => Some(midi::MidiMapping::load(path)?),
+        None => None,
+    };
+
+    let (recorder, record_tap) = match args.record {
+        Some(record_path) => {
+            let (recorder, tap) =
+                Recorder::spawn(record_path, 2, config.sample_rate.0).context("start recorder")?;
+            (Some(recorder), Some(tap))
+        }
+        None => (None, None),
+    };
+
+    let (scope_tap, scope) = scope::scope(
+        args.scope_length as usize,
+        args.fft_size,
+        config.sample_rate.0,
+    );
+    let analyzer = spectrum::Analyzer::new(args.fft_size, config.sample_rate.0);
+    let taps = Taps {
+        scope: scope_tap,
+        record: record_tap,
+    };
 
     // get file updates, keep watching until the end
-    let (_watcher, code_updates) = watch_path(Path::new(&path)).context("watch path")?;
+    let (code_sender, code_updates) = mpsc::channel();
+    let _watcher = watch_path(Path::new(&path), code_sender.clone()).context("watch path")?;
+    let transition = Transition {
+        quantize: args.quantize,
+        crossfade: (args.crossfade as u64 * config.sample_rate.0 as u64 / 1000) as usize,
+    };
+    let (handoff, code_status) = updater::spawn_updater(
+        code_updates,
+        config.sample_rate.0 as usize,
+        bpm,
+        transition,
+        routing.chains().len(),
+    )
+    .context("start updater")?;
+
+    let (control, control_receiver) = control::control_channel();
+
+    if let Some(address) = args.osc {
+        let targets = osc::OscTargets {
+            code_updates: code_sender.clone(),
+            control: control.clone(),
+            sample_data: sample_data.clone(),
+            record_state: recorder.as_ref().map(|r| r.state.clone()),
+        };
+        osc::spawn_osc_server(address, targets).context("start OSC server")?;
+    }
+
+    if midi_mapping.is_some() || args.midi_clock_in {
+        let mapper = midi::MidiMapper::new(midi_mapping.unwrap_or_default());
+        let follower = args
+            .midi_clock_in
+            .then(|| ClockFollower::new(control.clone(), sample_data.clone()));
+        #[cfg(target_os = "linux")]
+        midi::seq::spawn_midi_input(mapper, follower, control.clone())
+            .context("start MIDI input")?;
+        #[cfg(not(target_os = "linux"))]
+        {
+            let _ = (mapper, follower);
+            anyhow::bail!("MIDI input is only supported on Linux");
+        }
+    }
+    if args.midi_clock_out {
+        #[cfg(target_os = "linux")]
+        midi::seq::spawn_clock_output(sample_data.clone()).context("start MIDI clock output")?;
+        #[cfg(not(target_os = "linux"))]
+        anyhow::bail!("MIDI clock output is only supported on Linux");
+    }
+
+    let output = Output {
+        host_id: host.id(),
+        device,
+        config,
+        routing,
+    };
+    // played until ctrl-c or `q`, or the audio giving up
+    let (stop_sender, stop) = mpsc::channel();
+    let ctrl_c = stop_sender.clone();
+    ctrlc::set_handler(move || {
+        let _ = ctrl_c.send(());
+    })
+    .context("set ctrl-c handler")?;
+    let audio_stopped = stop_sender.clone();
 
     let sample_data_clone = sample_data.clone();
-    let audio_thread = thread::spawn(move || {
-        if let Err(e) = match config.sample_format() {
+    thread::spawn(move || {
+        if let Err(e) = match sample_format {
             cpal::SampleFormat::I8 => run_audio::<i8>(
-                &device,
-                &config.into(),
-                code_updates,
-                bpm,
+                output,
+                handoff,
+                control_receiver,
                 sample_data_clone,
+                taps,
+                input,
             ),
             cpal::SampleFormat::I16 => run_audio::<i16>(
-                &device,
-                &config.into(),
-                code_updates,
-                bpm,
+                output,
+                handoff,
+                control_receiver,
                 sample_data_clone,
+                taps,
+                input,
             ),
             // cpal::SampleFormat::I24 => run::<I24>(&device, &config.into()),
             cpal::SampleFormat::I32 => run_audio::<i32>(
-                &device,
-                &config.into(),
-                code_updates,
-                bpm,
+                output,
+                handoff,
+                control_receiver,
                 sample_data_clone,
+                taps,
+                input,
             ),
             // cpal::SampleFormat::I48 => run::<I48>(&device, &config.into()),
             cpal::SampleFormat::I64 => run_audio::<i64>(
-                &device,
-                &config.into(),
-                code_updates,
-                bpm,
+                output,
+                handoff,
+                control_receiver,
                 sample_data_clone,
+                taps,
+                input,
             ),
             cpal::SampleFormat::U8 => run_audio::<u8>(
-                &device,
-                &config.into(),
-                code_updates,
-                bpm,
+                output,
+                handoff,
+                control_receiver,
                 sample_data_clone,
+                taps,
+                input,
             ),
             cpal::SampleFormat::U16 => run_audio::<u16>(
-                &device,
-                &config.into(),
-                code_updates,
-                bpm,
+                output,
+                handoff,
+                control_receiver,
                 sample_data_clone,
+                taps,
+                input,
             ),
             // cpal::SampleFormat::U24 => run::<U24>(&device, &config.into()),
             cpal::SampleFormat::U32 => run_audio::<u32>(
-                &device,
-                &config.into(),
-                code_updates,
-                bpm,
+                output,
+                handoff,
+                control_receiver,
                 sample_data_clone,
+                taps,
+                input,
             ),
             // cpal::SampleFormat::U48 => run::<U48>(&device, &config.into()),
             cpal::SampleFormat::U64 => run_audio::<u64>(
-                &device,
-                &config.into(),
-                code_updates,
-                bpm,
+                output,
+                handoff,
+                control_receiver,
                 sample_data_clone,
+                taps,
+                input,
             ),
             cpal::SampleFormat::F32 => run_audio::<f32>(
-                &device,
-                &config.into(),
-                code_updates,
-                bpm,
+                output,
+                handoff,
+                control_receiver,
                 sample_data_clone,
+                taps,
+                input,
             ),
             cpal::SampleFormat::F64 => run_audio::<f64>(
-                &device,
-                &config.into(),
-                code_updates,
-                bpm,
+                output,
+                handoff,
+                control_receiver,
                 sample_data_clone,
+                taps,
+                input,
             ),
             sample_format => panic!("Unsupported sample format '{sample_format}'"),
         } {
             error!("run audio: {e:#}")
         }
+        let _ = audio_stopped.send(());
     });
 
     match args.headless {
@@ -257,6 +471,11 @@ fn main() -> Result<(), Box<dyn Error>> {
                 .with_timer(ChronoLocal::new(String::from("%H:%M:%S%.3f")))
                 .with_span_events(FmtSpan::NEW | FmtSpan::CLOSE)
                 .init();
+            if let Some(seconds) = args.stats {
+                timing::spawn_stats_printer(sample_data.clone(), Duration::from_secs(seconds))
+                    .context("start stats printer")?;
+            }
+            meter::spawn_headless_monitor(sample_data).context("start meter")?;
         }
         false => {
             // setup terminal
@@ -267,15 +486,27 @@ fn main() -> Result<(), Box<dyn Error>> {
             let backend = CrosstermBackend::new(stdout);
             let mut terminal = Terminal::new(backend)?;
 
+            // the editor pane follows the file too, to show edits made elsewhere
+            let (file_sender, file_changes) = mpsc::channel();
+            let _editor_watcher =
+                watch_path(Path::new(&path), file_sender).context("watch path for the editor")?;
+            let content = file_changes.recv().context("read file for the editor")?;
+
             let tick_rate = Duration::from_millis(16);
-            let res = run_app(
-                console_buffer,
-                &mut terminal,
-                tick_rate,
+            let app = App {
+                editor: Editor::new(&content),
+                path: PathBuf::from(&path),
+                file_changes,
+                code_updates: code_sender,
                 sample_data,
-                // scope,
+                control,
+                scope,
+                analyzer,
+                record_state: recorder.as_ref().map(|r| r.state.clone()),
+                code_status,
                 info,
-            );
+            };
+            let res = run_app(console_buffer, &mut terminal, tick_rate, app);
 
             // restore terminal
             disable_raw_mode()?;
@@ -286,168 +517,589 @@ fn main() -> Result<(), Box<dyn Error>> {
             )?;
             terminal.show_cursor()?;
             match res {
-                Ok(ExitStatus::ExitAll) => std::process::exit(0),
+                Ok(ExitStatus::ExitAll) => {
+                    let _ = stop_sender.send(());
+                }
                 Ok(ExitStatus::KeepAudio) => (),
                 Err(e) => println!("{e:?}"),
             };
         }
     }
-    audio_thread.join().unwrap();
+    let _ = stop.recv();
+    // save the take, its header would be left a few blocks short otherwise
+    if let Some(recorder) = recorder {
+        recorder.finish();
+    }
     Ok(())
 }
 
+#[derive(Clone, Copy)]
+enum DeviceKind {
+    Input,
+    Output,
+}
+
+/// Find a device by name, or the host's default one for "default"
+///
+/// Exits listing the available devices when none match
+fn find_device(host: &cpal::Host, name: &str, kind: DeviceKind) -> Result<cpal::Device> {
+    let (default, mut devices) = match kind {
+        DeviceKind::Input => (host.default_input_device(), host.input_devices()?.collect()),
+        DeviceKind::Output => (
+            host.default_output_device(),
+            host.output_devices()?.collect::<Vec<_>>(),
+        ),
+    };
+    let kind_name = match kind {
+        DeviceKind::Input => "input",
+        DeviceKind::Output => "output",
+    };
+
+    if name == "default" {
+        return default.with_context(|| format!("No default {kind_name} device found"));
+    }
+
+    let Some(position) = devices
+        .iter()
+        .position(|x| x.name().is_ok_and(|y| y == name))
+    else {
+        eprintln!("Couldn't find {kind_name} device '{name}'. Available options are:");
+        for dev_name in devices.iter().filter_map(|d| d.name().ok()) {
+            eprintln!("  {dev_name}");
+        }
+        eprintln!("Run `glicol-cli devices` for what each of them supports");
+        std::process::exit(1);
+    };
+
+    Ok(devices.swap_remove(position))
+}
+
+/// Extract the message from the error bytes returned by [`Engine::next_block`]
+pub(crate) fn decode_engine_error(raw_err: &[u8; 256]) -> Option<String> {
+    if raw_err[0] == 0 {
+        return None;
+    }
+
+    // message starts at the third byte and is padded with zeros
+    let msg = &raw_err[2..];
+    let len = msg.iter().position(|b| *b == 0).unwrap_or(msg.len());
+    Some(String::from_utf8_lossy(&msg[..len]).into_owned())
+}
+
 struct SampleData {
-    left_ptr: AtomicPtr<f32>,
-    right_ptr: AtomicPtr<f32>,
-    index: AtomicUsize,
+    /// Tempo currently played, as `f32` bits
+    bpm: AtomicU32,
     capacity: AtomicU32,
     paused: AtomicBool,
+    /// Position sent as MIDI clock
+    clock: ClockPosition,
+    /// Output levels shown by the meters
+    levels: Levels,
+    master: MasterState,
+    /// Output device, lost and reopened
+    device: DeviceStatus,
+    /// How long the callbacks take
+    timing: CallbackStats,
+}
+
+/// Where the callback sends the audio it plays, besides the device
+struct Taps {
+    scope: ScopeTap,
+    record: Option<RecordTap>,
+}
+
+/// The device played to, and how the stereo output of the engine is spread on its channels
+struct Output {
+    host_id: cpal::HostId,
+    device: cpal::Device,
+    config: cpal::StreamConfig,
+    routing: Routing,
+}
+
+/// Engine played by the callback, with the block being played
+struct Playing {
+    engine: Box<Engine<BLOCK_SIZE>>,
+    block: [Buffer<BLOCK_SIZE>; 2],
+    /// Chains routed on their own and their blocks, silent when missing from the code and
+    /// taken out of `block`
+    chains: Arc<[String]>,
+    chain_blocks: Vec<[Buffer<BLOCK_SIZE>; 2]>,
+    /// Position of the next frame to play in `block`
+    pos: usize,
+    /// Error returned by the engine with its last block, for the updater to report
+    error: Option<[u8; 256]>,
+}
+
+impl Playing {
+    /// Play a prepared engine, its chain blocks made on the updater thread for `chains`
+    fn new(prepared: PreparedEngine, chains: Arc<[String]>) -> Self {
+        let mut playing = Self {
+            engine: prepared.engine,
+            block: prepared.first_block,
+            chain_blocks: prepared.chain_blocks,
+            chains,
+            pos: 0,
+            error: None,
+        };
+        // the chains still hold the first block
+        playing.copy_chain_blocks();
+        playing
+    }
+
+    /// Hand the engine and its blocks back to the updater, to be freed there
+    fn retire(self, handoff: &mut EngineHandoff) {
+        handoff.retire(self.engine, self.chain_blocks);
+    }
+
+    fn copy_chain_blocks(&mut self) {
+        for (name, block) in self.chains.iter().zip(&mut self.chain_blocks) {
+            let node = self
+                .engine
+                .index_info
+                .get(name)
+                .and_then(|nodes| nodes.last());
+            let buffers = match node {
+                Some(&node) => &self.engine.context.graph[node].buffers[..],
+                None => &[],
+            };
+            for (channel, buffer) in block.iter_mut().enumerate() {
+                // mono chains play on both channels
+                match buffers.get(channel).or(buffers.first()) {
+                    Some(chain_buffer) => buffer.copy_from_slice(chain_buffer),
+                    None => buffer.fill(0.0),
+                }
+            }
+
+            // glicol sums the chains without a `~` into the mix, a routed one only plays on
+            // its own channels
+            if !name.contains('~') {
+                for (mix, chain) in self.block.iter_mut().zip(block.iter()) {
+                    for (mix_sample, chain_sample) in mix.iter_mut().zip(chain.iter()) {
+                        *mix_sample -= chain_sample;
+                    }
+                }
+            }
+        }
+    }
+
+    /// Frame of a chain of `chains`, the one of the last frame played
+    fn chain_frame(&self, index: usize) -> [f32; 2] {
+        let block = &self.chain_blocks[index];
+        [block[0][self.pos - 1], block[1][self.pos - 1]]
+    }
+
+    fn needs_block(&self) -> bool {
+        self.pos == BLOCK_SIZE
+    }
+
+    /// Copy the input to `~input` as `Engine::next_block` would, without building a `Vec`
+    fn write_input(&mut self, input: &[[f32; BLOCK_SIZE]; 2]) {
+        let Some(&node) = self
+            .engine
+            .index_info
+            .get("~input")
+            .and_then(|nodes| nodes.first())
+        else {
+            return;
+        };
+        let buffers = &mut self.engine.context.graph[node].buffers;
+        for (buffer, input) in buffers.iter_mut().zip(input) {
+            buffer.copy_from_slice(input);
+        }
+    }
+
+    fn next_frame(&mut self, input: Option<&[[f32; BLOCK_SIZE]; 2]>) -> [f32; 2] {
+        if self.needs_block() {
+            if let Some(input) = input {
+                self.write_input(input);
+            }
+            let (next_block, raw_err) = self.engine.next_block(vec![]);
+            for (buffer, next) in self.block.iter_mut().zip(next_block) {
+                buffer.copy_from_slice(next);
+            }
+            if raw_err[0] != 0 {
+                self.error = Some(raw_err);
+            }
+            self.copy_chain_blocks();
+            self.pos = 0;
+        }
+
+        let frame = [self.block[0][self.pos], self.block[1][self.pos]];
+        self.pos += 1;
+        frame
+    }
 }
 
 fn run_audio<T>(
-    device: &cpal::Device,
-    config: &cpal::StreamConfig,
-    code_updates: sync::mpsc::Receiver<String>,
-    bpm: f32,
+    output: Output,
+    handoff: EngineHandoff,
+    control: ControlReceiver,
     sample_data: Arc<SampleData>,
+    taps: Taps,
+    input: Option<(cpal::Device, SupportedStreamConfig)>,
 ) -> Result<(), anyhow::Error>
 where
     T: SizedSample + FromSample<f32>,
 {
+    let Output {
+        host_id,
+        device,
+        config,
+        routing,
+    } = output;
     let sr = config.sample_rate.0 as usize;
 
-    let mut engine = Engine::<BLOCK_SIZE>::new();
-    samples::load_samples_from_env(&mut engine);
+    // code is parsed on the updater thread, the callback only swaps in the prepared engines
+    let chains = routing.chains();
+    let playing = Playing {
+        engine: Box::new(Engine::<BLOCK_SIZE>::new()),
+        block: [Buffer::SILENT; 2],
+        chain_blocks: vec![[Buffer::SILENT; 2]; chains.len()],
+        chains: chains.clone(),
+        pos: BLOCK_SIZE,
+        error: None,
+    };
+    let bpm = f32::from_bits(sample_data.bpm.load(Ordering::Relaxed));
 
-    engine.set_sr(sr);
-    engine.set_bpm(bpm);
+    // the watcher keeps the input stream alive and opens it again along with the output
+    let (input_error_sender, input_errors) = mpsc::channel();
+    let (input_stream, input_bridge, reopen_input) = match input {
+        Some((input_device, input_config)) => {
+            let (stream, bridge) = input::run_input(
+                &input_device,
+                &input_config,
+                config.sample_rate.0,
+                &input_error_sender,
+            )
+            .context("run audio input")?;
+            let reopen = recovery::ReopenInput {
+                name: input_device.name()?,
+                error_sender: input_error_sender,
+            };
+            (Some(stream), Some(bridge), Some(reopen))
+        }
+        None => (None, None, None),
+    };
 
-    let channels = 2_usize; //config.channels as usize;
+    let callback = Arc::new(Mutex::new(Callback {
+        handoff,
+        control,
+        taps,
+        sr,
+        channels: config.channels as usize,
+        chain_frames: vec![[0.0; 2]; chains.len()],
+        routing,
+        chains,
+        playing,
+        fading: None,
+        pending: None,
+        bpm,
+        transport: Transport::new(sr, bpm),
+        constants: Constants::new(),
+        master: MasterBus::new(sr, &sample_data.master),
+        started: false,
+        input_bridge,
+        input_block: [[0.0; BLOCK_SIZE]; 2],
+        last_callback: None,
+        sample_data: sample_data.clone(),
+    }));
 
-    let mut prev_block: [glicol_synth::Buffer<BLOCK_SIZE>; 2] = [glicol_synth::Buffer::SILENT; 2];
+    let (error_sender, errors) = mpsc::channel();
+    let stream = recovery::open_stream::<T>(&device, &config, &callback, &error_sender)?;
+    stream.play()?;
+    let name = sample_data.device.name();
 
-    let ptr = prev_block.as_mut_ptr();
-    let prev_block_ptr = Arc::new(AtomicPtr::<glicol_synth::Buffer<BLOCK_SIZE>>::new(ptr));
-    let prev_block_len = Arc::new(AtomicUsize::new(prev_block.len()));
+    // reopen the stream with the same callback when the device goes away
+    recovery::watch_stream::<T>(
+        stream,
+        input_stream,
+        recovery::Reopen {
+            host_id,
+            name,
+            config,
+            callback,
+            error_sender,
+            input: reopen_input,
+        },
+        errors,
+        input_errors,
+        &sample_data.device,
+    )
+}
 
-    let mut prev_block_pos: usize = BLOCK_SIZE;
+/// State of the audio callback, kept when the stream is opened again
+struct Callback {
+    handoff: EngineHandoff,
+    control: ControlReceiver,
+    sample_data: Arc<SampleData>,
+    taps: Taps,
+    routing: Routing,
+    chains: Arc<[String]>,
+    sr: usize,
+    channels: usize,
+    playing: Playing,
+    /// Engine being replaced, still played until the end of the fade
+    fading: Option<(Playing, Crossfade)>,
+    /// Prepared engine waiting for its beat or bar, with the frames left until then
+    pending: Option<(PreparedEngine, usize)>,
+    bpm: f32,
+    transport: Transport,
+    constants: Constants,
+    master: MasterBus,
+    started: bool,
+    input_bridge: Option<InputBridge>,
+    input_block: [[f32; BLOCK_SIZE]; 2],
+    /// Frames of the chains routed on their own, with the volume and limiting of the mix
+    chain_frames: Vec<[f32; 2]>,
+    /// When the last callback was called and the length of its buffer
+    last_callback: Option<(cpal::StreamInstant, Duration)>,
+}
 
-    let stream = device.build_output_stream(
-        config,
-        move |data: &mut [T], _: &cpal::OutputCallbackInfo| {
-            match code_updates.try_recv() {
-                Ok(code) => engine.update_with_code(&code),
-                Err(TryRecvError::Empty) => {} // nothing new
-                Err(TryRecvError::Disconnected) => panic!("code updater is gone"), // closing down
-            };
+impl Callback {
+    /// Feed `~input` from a reopened input, handing back the old bridge to free outside the callback
+    fn replace_input(&mut self, bridge: InputBridge) -> Option<InputBridge> {
+        self.input_bridge.replace(bridge)
+    }
 
-            if sample_data.paused.load(Ordering::Relaxed) {
-                for d in &mut *data {
-                    *d = T::from_sample(0.);
+    fn process<T>(&mut self, data: &mut [T], timestamp: cpal::StreamInstant)
+    where
+        T: SizedSample + FromSample<f32>,
+    {
+        let (sr, channels) = (self.sr, self.channels);
+        let buffer = Duration::from_secs_f64((data.len() / channels) as f64 / sr as f64);
+        if let Some((last, last_buffer)) = self.last_callback.replace((timestamp, buffer)) {
+            let since_last = timestamp.duration_since(&last);
+            if since_last.is_some_and(|since_last| timing::is_gap(since_last, last_buffer)) {
+                self.sample_data.timing.record_gap();
+            }
+        }
+        let Self {
+            handoff,
+            control,
+            sample_data,
+            taps,
+            routing,
+            chains,
+            playing,
+            fading,
+            pending,
+            bpm,
+            transport,
+            constants,
+            master,
+            started,
+            input_bridge,
+            input_block,
+            chain_frames,
+            ..
+        } = self;
+        sample_data.device.tick();
+
+        while let Some(message) = control.try_recv() {
+            match message {
+                Control::SetBpm(new_bpm) => {
+                    *bpm = new_bpm;
+                    playing.engine.set_bpm(*bpm);
+                    if let Some((old, _)) = fading {
+                        old.engine.set_bpm(*bpm);
+                    }
+                    transport.set_bpm(*bpm);
+                    if let Some((prepared, wait)) = pending {
+                        prepared.engine.set_bpm(*bpm);
+                        // the boundary moved with the tempo
+                        if *started {
+                            *wait = transport.frames_to_boundary(prepared.transition.quantize);
+                        }
+                    }
+                    sample_data.bpm.store(bpm.to_bits(), Ordering::Relaxed);
                 }
-                return;
+                Control::SetConstant(name, value) => {
+                    constants.set(name, value);
+                    control::set_constant(&mut playing.engine, name, value);
+                    if let Some((old, _)) = fading {
+                        control::set_constant(&mut old.engine, name, value);
+                    }
+                    if let Some((prepared, _)) = pending {
+                        control::set_constant(&mut prepared.engine, name, value);
+                    }
+                }
+                Control::SetVolume(volume_db) => master.set_volume(volume_db),
+                Control::Mute(muted) => master.set_muted(muted),
             }
+        }
 
-            let block_step = data.len() / channels;
+        if let Some(mut prepared) = handoff.try_take() {
+            // the updater only knows the tempo at startup and none of the constants set
+            prepared.engine.set_bpm(*bpm);
+            constants.apply(&mut prepared.engine);
+            // nothing plays before the first update, no need to wait for it
+            let wait = match *started {
+                true => transport.frames_to_boundary(prepared.transition.quantize),
+                false => 0,
+            };
+            if let Some((older, _)) = pending.replace((prepared, wait)) {
+                handoff.retire(older.engine, older.chain_blocks);
+            }
+        }
 
-            let samples_left_ptr = sample_data.left_ptr.load(Ordering::SeqCst);
-            let samples_right_ptr = sample_data.right_ptr.load(Ordering::SeqCst);
+        let recording = taps.record.as_ref().is_some_and(RecordTap::is_recording);
 
-            let start_time = Instant::now();
+        if sample_data.paused.load(Ordering::Relaxed) {
+            if let Some(bridge) = input_bridge {
+                bridge.clear();
+            }
+            sample_data
+                .clock
+                .publish(transport.beats(), Instant::now(), false);
+            sample_data.levels.publish(&BlockLevels::default());
+            master.publish(&sample_data.master);
+            for d in &mut *data {
+                *d = T::from_sample(0.);
+            }
+            if let Some(tap) = taps.record.as_mut().filter(|_| recording) {
+                for _ in 0..data.len() / channels {
+                    tap.push_frame(&[0.0; 2]);
+                }
+            }
+            return;
+        }
 
-            let mut write_samples =
-                |block: &[glicol_synth::Buffer<BLOCK_SIZE>], sample_i: usize, i: usize| {
-                    for chan in 0..channels {
-                        let samples_i = sample_data.index.load(Ordering::SeqCst);
-                        unsafe {
-                            match chan {
-                                0 => samples_left_ptr.add(samples_i).write(block[chan][i]),
-                                1 => samples_right_ptr.add(samples_i).write(block[chan][i]),
-                                _ => panic!(),
-                            };
-                        };
+        let block_step = data.len() / channels;
 
-                        sample_data
-                            .index
-                            .store((samples_i + 1) % 200, Ordering::SeqCst);
+        let start_time = Instant::now();
+        sample_data
+            .clock
+            .publish(transport.beats(), start_time, *started);
+        let mut levels = BlockLevels::default();
+        let mut write_samples = |frame: [f32; 2], chain_frames: &[[f32; 2]], sample_i: usize| {
+            levels.add_frame(frame);
+            for chan in 0..channels {
+                let value: T = T::from_sample(routing.sample(chan, frame, chain_frames));
+                data[sample_i * channels + chan] = value;
+            }
 
-                        let value: T = T::from_sample(block[chan][i]);
-                        data[sample_i * channels + chan] = value;
-                    }
-                };
+            taps.scope.push_frame(frame);
+            if let Some(tap) = taps.record.as_mut().filter(|_| recording) {
+                tap.push_frame(&frame);
+            }
+        };
 
-            let ptr = prev_block_ptr.load(Ordering::Acquire);
-            let len = prev_block_len.load(Ordering::Acquire);
-            let prev_block: &mut [glicol_synth::Buffer<BLOCK_SIZE>] =
-                unsafe { std::slice::from_raw_parts_mut(ptr, len) };
+        for sample_i in 0..block_step {
+            // switch right on the boundary, even in the middle of a block
+            if let Some((_, 0)) = pending {
+                let (prepared, _) = pending.take().expect("matched above");
+                let crossfade = prepared.transition.crossfade;
+                let old = std::mem::replace(playing, Playing::new(prepared, chains.clone()));
 
-            let mut writes = 0;
+                if !*started {
+                    // fading from silence would only delay the first sound
+                    *started = true;
+                    // the first beat is the first frame of the code, the buffer is advanced over below
+                    transport.start(sample_i);
+                    old.retire(handoff);
+                } else if crossfade == 0 {
+                    old.retire(handoff);
+                } else if let Some((older, _)) = fading.replace((old, Crossfade::new(crossfade))) {
+                    // updated again while fading, cut the oldest one
+                    older.retire(handoff);
+                }
+            }
 
-            for i in prev_block_pos..BLOCK_SIZE {
-                write_samples(prev_block, writes, i);
-                writes += 1;
+            // the input is read at the pace of the latest engine, the fading one gets
+            // the same blocks slightly shifted, which isn't noticeable as it fades out
+            if playing.needs_block() {
+                if let Some(bridge) = input_bridge {
+                    bridge.read_block(input_block);
+                }
             }
+            let input = input_bridge.as_ref().map(|_| &*input_block);
 
-            prev_block_pos = BLOCK_SIZE;
-            while writes < block_step {
-                let (block, raw_err) = engine.next_block(vec![]);
-                if raw_err[0] != 0 {
-                    let raw_msg = Vec::from(&raw_err[1..]);
-                    match String::from_utf8(raw_msg) {
-                        Ok(msg) => error!("get next block of engine: {msg}"),
-                        Err(e) => error!("got error from engine but unable to decode it: {e}"),
+            let mut frame = playing.next_frame(input);
+            if let Some(raw_err) = playing.error.take() {
+                handoff.report_error(raw_err);
+            }
+            for (index, chain_frame) in chain_frames.iter_mut().enumerate() {
+                *chain_frame = playing.chain_frame(index);
+            }
+            if let Some((old, fade)) = fading {
+                let old_frame = old.next_frame(input);
+                if let Some(raw_err) = old.error.take() {
+                    handoff.report_error(raw_err);
+                }
+                let (old_gain, new_gain) = fade.next_gains();
+                for (sample, old_sample) in frame.iter_mut().zip(old_frame) {
+                    *sample = *sample * new_gain + old_sample * old_gain;
+                }
+                for (index, chain_frame) in chain_frames.iter_mut().enumerate() {
+                    for (sample, old_sample) in chain_frame.iter_mut().zip(old.chain_frame(index)) {
+                        *sample = *sample * new_gain + old_sample * old_gain;
                     }
                 }
 
-                if writes + BLOCK_SIZE <= block_step {
-                    for i in 0..BLOCK_SIZE {
-                        write_samples(block, writes, i);
-                        writes += 1;
-                    }
-                } else {
-                    let e = block_step - writes;
-                    for i in 0..e {
-                        write_samples(block, writes, i);
-                        writes += 1;
-                    }
-                    for (buffer, block) in prev_block.iter_mut().zip(block.iter()) {
-                        buffer.copy_from_slice(block);
-                    }
-                    prev_block_pos = e;
-                    break;
+                if fade.is_done() {
+                    let (old, _) = fading.take().expect("matched above");
+                    old.retire(handoff);
                 }
             }
 
-            let elapsed_time = start_time.elapsed().as_nanos() as f32;
-            let allowed_ns = block_step as f32 * 1_000_000_000.0 / sr as f32;
-            let perc = elapsed_time / allowed_ns;
-            sample_data
-                .capacity
-                .store(perc.to_bits(), Ordering::Release);
-
-            // rms = rms
-            //     .into_iter()
-            //     .map(|x| (x / block_step as f32).sqrt())
-            //     .collect();
-            // left rms[0] right rms[1]
-
-            // let ptr_l = ptr_rb_left_clone.load(Ordering::SeqCst);
-            // let ptr_r = ptr_rb_right_clone.load(Ordering::SeqCst);
-
-            // let len = RB_SIZE;
-            // let idx = index_clone.load(Ordering::SeqCst);
-            // unsafe {
-            //     ptr_l.add(idx).write(rms[0]);
-            //     ptr_r.add(idx).write(rms[1]);
-            // };
-            // index_clone.store((idx + 1) % RB_SIZE, Ordering::SeqCst); // from 0, 1, 2, RB_SIZE-1;
-        },
-        |err| error!("an error occurred on stream: {err}"),
-        None,
-    )?;
-    stream.play()?;
+            let frame = master.process(frame);
+            for sample in chain_frames.iter_mut().flatten() {
+                *sample = master.process_chain(*sample);
+            }
+            write_samples(frame, chain_frames, sample_i);
+
+            if let Some((_, wait)) = pending {
+                *wait -= 1;
+            }
+        }
+        transport.advance(block_step);
+        taps.scope.flush();
+        levels.set_clipped(master.take_clipped());
+        sample_data.levels.publish(&levels);
+        master.publish(&sample_data.master);
+
+        let elapsed_time = start_time.elapsed().as_nanos() as f32;
+        let allowed_ns = block_step as f32 * 1_000_000_000.0 / sr as f32;
+        let perc = elapsed_time / allowed_ns;
+        sample_data
+            .capacity
+            .store(perc.to_bits(), Ordering::Release);
+        sample_data.timing.record(start_time.elapsed(), buffer);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Playing, BLOCK_SIZE};
 
-    loop {
-        thread::park() // wait forever
+    use std::sync::Arc;
+
+    use glicol::Engine;
+    use glicol_synth::Buffer;
+
+    use crate::{quantize::Quantize, updater::PreparedEngine, updater::Transition};
+
+    #[test]
+    fn take_routed_chains_out_of_the_mix() {
+        let mut engine = Box::new(Engine::<BLOCK_SIZE>::new());
+        engine.update_with_code("front: constsig 0.25\nrear: constsig 0.5");
+        let (block, _) = engine.next_block(vec![]);
+        let first_block = [block[0].clone(), block[1].clone()];
+        assert_eq!(first_block[0][0], 0.75);
+
+        let prepared = PreparedEngine {
+            engine,
+            first_block,
+            chain_blocks: vec![[Buffer::SILENT; 2]],
+            transition: Transition {
+                quantize: Quantize::None,
+                crossfade: 0,
+            },
+        };
+        let mut playing = Playing::new(prepared, Arc::from([String::from("rear")]));
+        for _ in 0..BLOCK_SIZE * 2 {
+            assert_eq!(playing.next_frame(None), [0.25; 2]);
+            assert_eq!(playing.chain_frame(0), [0.5; 2]);
+        }
     }
 }
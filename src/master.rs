@@ -0,0 +1,297 @@
+use std::{
+    f32::consts::PI,
+    sync::atomic::{AtomicBool, AtomicU32, Ordering},
+};
+
+pub(crate) const MIN_VOLUME_DB: f32 = -60.0;
+pub(crate) const MAX_VOLUME_DB: f32 = 6.0;
+
+/// Time for volume changes and mutes to settle, short enough to feel instant without clicks
+const SMOOTHING_SECONDS: f32 = 0.01;
+
+/// Time for the limiter to recover from a peak
+const RELEASE_SECONDS: f32 = 0.1;
+
+/// Cutoff of the DC blocker
+const DC_CUTOFF_HZ: f32 = 10.0;
+
+pub(crate) fn db_to_gain(db: f32) -> f32 {
+    10f32.powf(db / 20.0)
+}
+
+/// Check the limiter ceiling given on the command line, in dBFS
+pub(crate) fn parse_ceiling(arg: &str) -> Result<f32, String> {
+    let ceiling: f32 = arg.parse().map_err(|e| format!("{e}"))?;
+    if !(-24.0..=0.0).contains(&ceiling) {
+        return Err(String::from("must be between -24 and 0 dBFS"));
+    }
+    Ok(ceiling)
+}
+
+/// Master bus settings and activity, published by the audio callback
+pub(crate) struct MasterState {
+    /// Limiter ceiling in dBFS, fixed at startup
+    pub ceiling_db: f32,
+    /// `f32` bits
+    volume_db: AtomicU32,
+    muted: AtomicBool,
+    /// Strongest limiting of the last callback in dB, as `f32` bits
+    gain_reduction_db: AtomicU32,
+}
+
+impl MasterState {
+    pub fn new(ceiling_db: f32) -> Self {
+        Self {
+            ceiling_db,
+            volume_db: AtomicU32::new(0f32.to_bits()),
+            muted: AtomicBool::new(false),
+            gain_reduction_db: AtomicU32::new(0f32.to_bits()),
+        }
+    }
+
+    pub fn volume_db(&self) -> f32 {
+        f32::from_bits(self.volume_db.load(Ordering::Relaxed))
+    }
+
+    pub fn is_muted(&self) -> bool {
+        self.muted.load(Ordering::Relaxed)
+    }
+
+    pub fn gain_reduction_db(&self) -> f32 {
+        f32::from_bits(self.gain_reduction_db.load(Ordering::Relaxed))
+    }
+}
+
+/// First order high-pass removing any DC offset
+#[derive(Default)]
+struct DcBlocker {
+    last_input: f32,
+    last_output: f32,
+}
+
+impl DcBlocker {
+    fn process(&mut self, input: f32, pole: f32) -> f32 {
+        let output = input - self.last_input + pole * self.last_output;
+        self.last_input = input;
+        self.last_output = output;
+        output
+    }
+}
+
+/// Last stage before the output: DC blocker, volume, mute and brickwall limiter
+pub(crate) struct MasterBus {
+    ceiling: f32,
+    volume_db: f32,
+    muted: bool,
+    /// Gain of the volume and mute, computed when they change
+    target_gain: f32,
+    /// Volume gain, smoothed toward the target
+    gain: f32,
+    limiter_gain: f32,
+    /// Lowest limiter gain since the last publish
+    lowest_limiter_gain: f32,
+    /// A frame went over the ceiling since the last take
+    clipped: bool,
+    dc_blockers: [DcBlocker; 2],
+    dc_pole: f32,
+    smoothing: f32,
+    release: f32,
+}
+
+impl MasterBus {
+    pub fn new(sample_rate: usize, state: &MasterState) -> Self {
+        let sample_rate = sample_rate as f32;
+        let volume_db = state.volume_db();
+        let mut bus = Self {
+            ceiling: db_to_gain(state.ceiling_db),
+            volume_db,
+            muted: state.is_muted(),
+            target_gain: 0.0,
+            gain: db_to_gain(volume_db),
+            limiter_gain: 1.0,
+            lowest_limiter_gain: 1.0,
+            clipped: false,
+            dc_blockers: Default::default(),
+            dc_pole: 1.0 - 2.0 * PI * DC_CUTOFF_HZ / sample_rate,
+            smoothing: 1.0 - (-1.0 / (SMOOTHING_SECONDS * sample_rate)).exp(),
+            release: 1.0 - (-1.0 / (RELEASE_SECONDS * sample_rate)).exp(),
+        };
+        bus.update_target_gain();
+        bus
+    }
+
+    pub fn set_volume(&mut self, volume_db: f32) {
+        self.volume_db = volume_db.clamp(MIN_VOLUME_DB, MAX_VOLUME_DB);
+        self.update_target_gain();
+    }
+
+    pub fn set_muted(&mut self, muted: bool) {
+        self.muted = muted;
+        self.update_target_gain();
+    }
+
+    fn update_target_gain(&mut self) {
+        self.target_gain = match self.muted {
+            true => 0.0,
+            false => db_to_gain(self.volume_db),
+        };
+    }
+
+    pub fn process(&mut self, frame: [f32; 2]) -> [f32; 2] {
+        self.gain += (self.target_gain - self.gain) * self.smoothing;
+
+        let mut frame = frame;
+        for (sample, dc_blocker) in frame.iter_mut().zip(&mut self.dc_blockers) {
+            if !sample.is_finite() {
+                // a division by zero in the code shouldn't get stuck in the filter
+                *dc_blocker = DcBlocker::default();
+                *sample = 0.0;
+            }
+            *sample = dc_blocker.process(*sample, self.dc_pole) * self.gain;
+        }
+
+        // instant attack, the hard clip below only catches what the smoothing lets through
+        let peak = frame[0].abs().max(frame[1].abs());
+        let needed = match peak > self.ceiling {
+            true => self.ceiling / peak,
+            false => 1.0,
+        };
+        self.clipped |= needed < 1.0;
+        if needed < self.limiter_gain {
+            self.limiter_gain = needed;
+        } else {
+            self.limiter_gain += (needed - self.limiter_gain) * self.release;
+        }
+        self.lowest_limiter_gain = self.lowest_limiter_gain.min(self.limiter_gain);
+
+        frame.map(|sample| (sample * self.limiter_gain).clamp(-self.ceiling, self.ceiling))
+    }
+
+    /// Volume and limiting of the last frame processed, for a chain routed on its own
+    pub fn process_chain(&self, sample: f32) -> f32 {
+        match sample.is_finite() {
+            true => (sample * self.gain * self.limiter_gain).clamp(-self.ceiling, self.ceiling),
+            false => 0.0,
+        }
+    }
+
+    /// Whether the limiter had to catch a frame over the ceiling since the last call
+    pub fn take_clipped(&mut self) -> bool {
+        std::mem::take(&mut self.clipped)
+    }
+
+    /// Share the settings and limiting of the frames processed since the last call
+    pub fn publish(&mut self, state: &MasterState) {
+        state
+            .volume_db
+            .store(self.volume_db.to_bits(), Ordering::Relaxed);
+        state.muted.store(self.muted, Ordering::Relaxed);
+        let reduction = -20.0 * self.lowest_limiter_gain.log10();
+        state
+            .gain_reduction_db
+            .store(reduction.to_bits(), Ordering::Relaxed);
+        self.lowest_limiter_gain = self.limiter_gain;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{db_to_gain, parse_ceiling, MasterBus, MasterState};
+
+    use crate::meter::{BlockLevels, Levels};
+
+    fn bus(ceiling_db: f32) -> (MasterBus, MasterState) {
+        let state = MasterState::new(ceiling_db);
+        (MasterBus::new(48000, &state), state)
+    }
+
+    #[test]
+    fn limit_to_the_ceiling() {
+        let (mut bus, state) = bus(-6.0);
+        let ceiling = db_to_gain(-6.0);
+
+        // a `mul 100` mistake
+        for i in 0..4800 {
+            let sample = 100.0 * (i as f32 * 0.05).sin();
+            let frame = bus.process([sample, -sample]);
+            assert!(frame.iter().all(|s| s.abs() <= ceiling), "{frame:?}");
+        }
+        bus.publish(&state);
+        assert!(state.gain_reduction_db() > 30.0);
+
+        // recovers once the level is back down
+        for i in 0..48000 {
+            bus.process([0.1 * (i as f32 * 0.05).sin(); 2]);
+        }
+        bus.publish(&state);
+        bus.publish(&state);
+        assert!(state.gain_reduction_db() < 0.1);
+    }
+
+    #[test]
+    fn clip_over_the_ceiling() {
+        let (mut bus, _) = bus(-1.0);
+        let levels = Levels::new();
+
+        let mut block = BlockLevels::default();
+        for i in 0..480 {
+            block.add_frame(bus.process([0.8 * (i as f32 * 0.05).sin(); 2]));
+        }
+        block.set_clipped(bus.take_clipped());
+        levels.publish(&block);
+        assert!(!levels.take().2);
+
+        // the output stays under the ceiling, the clip light still turns on
+        let mut block = BlockLevels::default();
+        for i in 0..480 {
+            block.add_frame(bus.process([2.0 * (i as f32 * 0.05).sin(); 2]));
+        }
+        block.set_clipped(bus.take_clipped());
+        levels.publish(&block);
+        let (peak, _, clipped) = levels.take();
+        assert!(peak[0] <= db_to_gain(-1.0));
+        assert!(clipped);
+        assert!(!bus.take_clipped());
+    }
+
+    #[test]
+    fn block_dc_and_non_finite_samples() {
+        let (mut bus, _) = bus(0.0);
+        let mut last = [1.0; 2];
+        for _ in 0..48000 {
+            last = bus.process([0.5, f32::NAN]);
+        }
+        assert!(last[0].abs() < 1e-3);
+        assert_eq!(last[1], 0.0);
+    }
+
+    #[test]
+    fn fade_volume_and_mute() {
+        let (mut bus, state) = bus(0.0);
+        bus.set_volume(-6.0);
+        bus.set_muted(true);
+        bus.publish(&state);
+        assert_eq!(state.volume_db(), -6.0);
+        assert!(state.is_muted());
+
+        // the first frames still fade out
+        let first = bus.process([0.5; 2]);
+        assert!(first[0] > 0.0);
+        for _ in 0..4800 {
+            bus.process([0.5; 2]);
+        }
+        assert!(bus.process([0.5; 2])[0].abs() < 1e-3);
+
+        bus.set_muted(false);
+        bus.set_volume(100.0);
+        bus.publish(&state);
+        assert_eq!(state.volume_db(), 6.0);
+    }
+
+    #[test]
+    fn accept_ceilings() {
+        assert_eq!(parse_ceiling("-1"), Ok(-1.0));
+        assert!(parse_ceiling("3").is_err());
+        assert!(parse_ceiling("loud").is_err());
+    }
+}
@@ -0,0 +1,210 @@
+use std::{
+    sync::{
+        atomic::{AtomicBool, AtomicU32, Ordering},
+        Arc,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+use anyhow::{Context, Result};
+use tracing::warn;
+
+use crate::SampleData;
+
+/// How long the highest peak stays on the meters
+const PEAK_HOLD: Duration = Duration::from_millis(1500);
+
+/// How often the headless monitor checks for clipping
+const MONITOR_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Convert a linear level to dBFS
+pub(crate) fn to_db(level: f32) -> f32 {
+    20.0 * level.max(1e-6).log10()
+}
+
+/// Levels measured by the audio callback, read by the TUI or the headless monitor
+pub(crate) struct Levels {
+    /// Highest peak since the last read, as `f32` bits
+    peak: [AtomicU32; 2],
+    /// RMS of the last callback, as `f32` bits
+    rms: [AtomicU32; 2],
+    /// The master bus limited a frame over its ceiling since the last read
+    clipped: AtomicBool,
+}
+
+impl Levels {
+    pub fn new() -> Self {
+        Self {
+            peak: [AtomicU32::new(0), AtomicU32::new(0)],
+            rms: [AtomicU32::new(0), AtomicU32::new(0)],
+            clipped: AtomicBool::new(false),
+        }
+    }
+
+    /// Called by the callback once it wrote its frames
+    pub fn publish(&self, block: &BlockLevels) {
+        for channel in 0..2 {
+            // positive floats order like their bits
+            self.peak[channel].fetch_max(block.peak[channel].to_bits(), Ordering::Relaxed);
+            let rms = match block.frames {
+                0 => 0.0,
+                frames => (block.sum_squares[channel] / frames as f32).sqrt(),
+            };
+            self.rms[channel].store(rms.to_bits(), Ordering::Relaxed);
+        }
+        if block.clipped {
+            self.clipped.store(true, Ordering::Relaxed);
+        }
+    }
+
+    /// Peaks and clipping since the last call, with the latest RMS
+    pub fn take(&self) -> ([f32; 2], [f32; 2], bool) {
+        let peak = self
+            .peak
+            .each_ref()
+            .map(|peak| f32::from_bits(peak.swap(0, Ordering::Relaxed)));
+        let rms = self
+            .rms
+            .each_ref()
+            .map(|rms| f32::from_bits(rms.load(Ordering::Relaxed)));
+        (peak, rms, self.clipped.swap(false, Ordering::Relaxed))
+    }
+}
+
+/// Levels of the frames played by one callback, cheap enough to compute there
+#[derive(Default)]
+pub(crate) struct BlockLevels {
+    peak: [f32; 2],
+    sum_squares: [f32; 2],
+    frames: usize,
+    clipped: bool,
+}
+
+impl BlockLevels {
+    pub fn add_frame(&mut self, frame: [f32; 2]) {
+        for (channel, sample) in frame.into_iter().enumerate() {
+            let level = sample.abs();
+            self.peak[channel] = self.peak[channel].max(level);
+            self.sum_squares[channel] += sample * sample;
+        }
+        self.frames += 1;
+    }
+
+    /// Whether the master bus had to limit these frames, which it kept under the ceiling
+    pub fn set_clipped(&mut self, clipped: bool) {
+        self.clipped |= clipped;
+    }
+}
+
+/// What the TUI meters show, with the held peaks and the latched clip light
+pub(crate) struct Meters {
+    pub peak: [f32; 2],
+    pub rms: [f32; 2],
+    /// Highest recent peaks and when they were reached
+    hold: [(f32, Instant); 2],
+    /// Stays on until cleared
+    pub clipped: bool,
+}
+
+impl Meters {
+    pub fn new(now: Instant) -> Self {
+        Self {
+            peak: [0.0; 2],
+            rms: [0.0; 2],
+            hold: [(0.0, now); 2],
+            clipped: false,
+        }
+    }
+
+    /// Read the latest levels, returning whether the clip light just turned on
+    pub fn update(&mut self, levels: &Levels, now: Instant) -> bool {
+        let (peak, rms, clipped) = levels.take();
+        self.peak = peak;
+        self.rms = rms;
+        for (hold, peak) in self.hold.iter_mut().zip(peak) {
+            if peak >= hold.0 || now.duration_since(hold.1) > PEAK_HOLD {
+                *hold = (peak, now);
+            }
+        }
+
+        let newly_clipped = clipped && !self.clipped;
+        self.clipped |= clipped;
+        newly_clipped
+    }
+
+    pub fn held_peak(&self, channel: usize) -> f32 {
+        self.hold[channel].0
+    }
+}
+
+/// Warn about clipping when there is no TUI to show it
+pub(crate) fn spawn_headless_monitor(sample_data: Arc<SampleData>) -> Result<()> {
+    thread::Builder::new()
+        .name(String::from("meter"))
+        .spawn(move || loop {
+            thread::sleep(MONITOR_INTERVAL);
+            let (peak, _, clipped) = sample_data.levels.take();
+            if clipped {
+                warn!(
+                    "output went over the ceiling, limited to a peak at {:+.1} dBFS",
+                    to_db(peak[0].max(peak[1]))
+                );
+            }
+        })
+        .context("spawn meter thread")?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{to_db, BlockLevels, Levels, Meters, PEAK_HOLD};
+
+    use std::time::{Duration, Instant};
+
+    fn publish(levels: &Levels, frames: &[[f32; 2]], clipped: bool) {
+        let mut block = BlockLevels::default();
+        for frame in frames {
+            block.add_frame(*frame);
+        }
+        block.set_clipped(clipped);
+        levels.publish(&block);
+    }
+
+    #[test]
+    fn measure_blocks() {
+        let levels = Levels::new();
+        publish(&levels, &[[0.5, -0.25], [-0.5, 0.0]], false);
+        publish(&levels, &[[0.5, 0.0]], false);
+
+        let (peak, rms, clipped) = levels.take();
+        assert_eq!(peak, [0.5, 0.25]);
+        assert_eq!(rms, [0.5, 0.0]);
+        assert!(!clipped);
+        assert_eq!(levels.take().0, [0.0, 0.0]);
+
+        publish(&levels, &[[0.0, -0.9]], true);
+        assert!(levels.take().2);
+        assert!((to_db(0.5) + 6.02).abs() < 0.01);
+    }
+
+    #[test]
+    fn hold_peaks_and_latch_clipping() {
+        let levels = Levels::new();
+        let start = Instant::now();
+        let mut meters = Meters::new(start);
+
+        publish(&levels, &[[0.8, 0.9]], true);
+        assert!(meters.update(&levels, start));
+        publish(&levels, &[[0.2, 0.1]], false);
+        assert!(!meters.update(&levels, start + Duration::from_millis(100)));
+        assert_eq!(meters.peak, [0.2, 0.1]);
+        assert_eq!(meters.held_peak(0), 0.8);
+        assert!(meters.clipped);
+
+        publish(&levels, &[[0.2, 0.1]], false);
+        meters.update(&levels, start + PEAK_HOLD * 2);
+        assert_eq!(meters.held_peak(0), 0.2);
+        assert!(meters.clipped);
+    }
+}
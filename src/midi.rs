@@ -0,0 +1,561 @@
+use std::{fs, path::Path};
+
+use anyhow::{bail, Context, Result};
+
+/// MIDI messages glicol-cli reacts to, channels are 0-based
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum MidiMessage {
+    NoteOn {
+        channel: u8,
+        note: u8,
+        velocity: u8,
+    },
+    NoteOff {
+        channel: u8,
+        note: u8,
+    },
+    ControlChange {
+        channel: u8,
+        controller: u8,
+        value: u8,
+    },
+    /// Sent 24 times per quarter note
+    Clock,
+    Start,
+    Stop,
+    Continue,
+}
+
+/// A controller driving a constant of the code
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct CcBinding {
+    pub controller: u8,
+    pub constant: &'static str,
+    /// Values for the controller at 0 and 127
+    pub range: (f32, f32),
+}
+
+/// Constants driven by the notes played, for a monophonic track
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct NoteBinding {
+    /// Gets the frequency of the last note held, in Hz
+    pub pitch: &'static str,
+    /// Gets the velocity between 0 and 1, 0 once every note is released
+    pub gate: &'static str,
+}
+
+/// How MIDI input drives the code, read from a mapping file like:
+///
+/// ```text
+/// # only listen to the first channel, all of them otherwise
+/// channel 1
+/// # CC 74 drives `~cutoff: constsig 1000` between 200 and 5000
+/// cc 74 ~cutoff 200 5000
+/// # CC 1 drives `~amp` between 0 and 1
+/// cc 1 ~amp
+/// # notes set `~lead` to their frequency and `~lead_gate` to their velocity
+/// notes ~lead
+/// ```
+#[derive(Clone, Debug, Default, PartialEq)]
+pub(crate) struct MidiMapping {
+    pub channel: Option<u8>,
+    pub controllers: Vec<CcBinding>,
+    pub notes: Option<NoteBinding>,
+}
+
+/// Names live as long as the program, so the audio callback can hold them without allocating
+fn leak(name: &str) -> &'static str {
+    Box::leak(name.to_owned().into_boxed_str())
+}
+
+impl MidiMapping {
+    pub fn load(path: &Path) -> Result<Self> {
+        let content =
+            fs::read_to_string(path).with_context(|| format!("read {}", path.display()))?;
+        Self::parse(&content).with_context(|| format!("parse {}", path.display()))
+    }
+
+    pub fn parse(content: &str) -> Result<Self> {
+        let mut mapping = Self::default();
+
+        for (i, line) in content.lines().enumerate() {
+            let line = line.split('#').next().unwrap_or_default();
+            let words = line.split_whitespace().collect::<Vec<_>>();
+            let line_number = i + 1;
+
+            match words.as_slice() {
+                [] => {}
+                ["channel", channel] => {
+                    let channel = channel
+                        .parse::<u8>()
+                        .ok()
+                        .filter(|c| (1..=16).contains(c))
+                        .with_context(|| format!("line {line_number}: invalid channel"))?;
+                    mapping.channel = Some(channel - 1);
+                }
+                ["cc", controller, constant, range @ ..] => {
+                    let controller = controller
+                        .parse::<u8>()
+                        .ok()
+                        .filter(|c| *c < 128)
+                        .with_context(|| format!("line {line_number}: invalid CC number"))?;
+                    let range = match range {
+                        [] => (0.0, 1.0),
+                        [min, max] => (
+                            min.parse()
+                                .with_context(|| format!("line {line_number}: invalid min"))?,
+                            max.parse()
+                                .with_context(|| format!("line {line_number}: invalid max"))?,
+                        ),
+                        _ => bail!(
+                            "line {line_number}: expected `cc <number> <constant> [<min> <max>]`"
+                        ),
+                    };
+                    mapping.controllers.push(CcBinding {
+                        controller,
+                        constant: leak(constant),
+                        range,
+                    });
+                }
+                ["notes", pitch, gate @ ..] => {
+                    let gate = match gate {
+                        [] => format!("{pitch}_gate"),
+                        [gate] => gate.to_string(),
+                        _ => bail!("line {line_number}: expected `notes <pitch> [<gate>]`"),
+                    };
+                    mapping.notes = Some(NoteBinding {
+                        pitch: leak(pitch),
+                        gate: leak(&gate),
+                    });
+                }
+                [keyword, ..] => bail!("line {line_number}: unknown keyword `{keyword}`"),
+            }
+        }
+
+        Ok(mapping)
+    }
+}
+
+/// Frequency of a MIDI note, in Hz
+pub(crate) fn note_to_hz(note: u8) -> f32 {
+    440.0 * 2f32.powf((note as f32 - 69.0) / 12.0)
+}
+
+/// Turns MIDI messages into constant values following a mapping
+pub(crate) struct MidiMapper {
+    mapping: MidiMapping,
+    /// Notes held, the last one sounding
+    held: Vec<u8>,
+}
+
+impl MidiMapper {
+    pub fn new(mapping: MidiMapping) -> Self {
+        Self {
+            mapping,
+            held: vec![],
+        }
+    }
+
+    /// Call `set` with the constants changed by `message`
+    pub fn handle(&mut self, message: MidiMessage, mut set: impl FnMut(&'static str, f32)) {
+        let channel = match message {
+            MidiMessage::NoteOn { channel, .. }
+            | MidiMessage::NoteOff { channel, .. }
+            | MidiMessage::ControlChange { channel, .. } => channel,
+            // handled by the clock follower
+            MidiMessage::Clock | MidiMessage::Start | MidiMessage::Stop | MidiMessage::Continue => {
+                return
+            }
+        };
+        if self.mapping.channel.is_some_and(|c| c != channel) {
+            return;
+        }
+
+        match message {
+            MidiMessage::ControlChange {
+                controller, value, ..
+            } => {
+                for binding in &self.mapping.controllers {
+                    if binding.controller == controller {
+                        let (min, max) = binding.range;
+                        set(binding.constant, min + (max - min) * value as f32 / 127.0);
+                    }
+                }
+            }
+            // a note on with no velocity is a note off
+            MidiMessage::NoteOn { note, velocity, .. } if velocity > 0 => {
+                let Some(notes) = &self.mapping.notes else {
+                    return;
+                };
+                self.held.retain(|held| *held != note);
+                self.held.push(note);
+                set(notes.pitch, note_to_hz(note));
+                set(notes.gate, velocity as f32 / 127.0);
+            }
+            MidiMessage::NoteOn { note, .. } | MidiMessage::NoteOff { note, .. } => {
+                let Some(notes) = &self.mapping.notes else {
+                    return;
+                };
+                let was_sounding = self.held.last() == Some(&note);
+                self.held.retain(|held| *held != note);
+                match self.held.last() {
+                    // back to the previous note still held
+                    Some(previous) if was_sounding => set(notes.pitch, note_to_hz(*previous)),
+                    Some(_) => {}
+                    None => set(notes.gate, 0.0),
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+/// ALSA sequencer ports, other platforms have no MIDI support yet
+#[cfg(target_os = "linux")]
+pub(crate) mod seq {
+    use std::{
+        ffi::CString,
+        io,
+        sync::{atomic::Ordering, Arc},
+        thread,
+        time::{Duration, Instant},
+    };
+
+    use alsa::seq::{EvCtrl, EvNote, EvQueueControl, Event, EventType, PortCap, PortType, Seq};
+    use anyhow::{Context, Result};
+    use tracing::{debug, error, info, warn};
+
+    use super::{MidiMapper, MidiMessage};
+    use crate::{
+        clock::{ClockFollower, ClockGenerator},
+        control::{Control, ControlSender},
+        SampleData,
+    };
+
+    /// How often the clock output checks whether ticks are due
+    const CLOCK_POLL_INTERVAL: Duration = Duration::from_millis(1);
+
+    fn to_message(event: &Event) -> Option<MidiMessage> {
+        match event.get_type() {
+            EventType::Noteon => event.get_data::<EvNote>().map(|n| MidiMessage::NoteOn {
+                channel: n.channel,
+                note: n.note,
+                velocity: n.velocity,
+            }),
+            EventType::Noteoff => event.get_data::<EvNote>().map(|n| MidiMessage::NoteOff {
+                channel: n.channel,
+                note: n.note,
+            }),
+            EventType::Controller => {
+                event
+                    .get_data::<EvCtrl>()
+                    .map(|c| MidiMessage::ControlChange {
+                        channel: c.channel,
+                        controller: c.param as u8,
+                        value: c.value.clamp(0, 127) as u8,
+                    })
+            }
+            EventType::Clock => Some(MidiMessage::Clock),
+            EventType::Start => Some(MidiMessage::Start),
+            EventType::Stop => Some(MidiMessage::Stop),
+            EventType::Continue => Some(MidiMessage::Continue),
+            _ => None,
+        }
+    }
+
+    /// Open a sequencer client with a virtual input port other clients can connect to
+    ///
+    /// Returns the client and port numbers, e.g. to use with `aconnect`.
+    pub(crate) fn spawn_midi_input(
+        mut mapper: MidiMapper,
+        mut follower: Option<ClockFollower>,
+        control: ControlSender,
+    ) -> Result<(i32, i32)> {
+        let seq = Seq::open(None, Some(alsa::Direction::Capture), false)
+            .context("open ALSA sequencer")?;
+        seq.set_client_name(&CString::new("glicol-cli")?)?;
+        let port = seq
+            .create_simple_port(
+                &CString::new("midi in")?,
+                PortCap::WRITE | PortCap::SUBS_WRITE,
+                PortType::MIDI_GENERIC | PortType::APPLICATION,
+            )
+            .context("create MIDI input port")?;
+        let client = seq.client_id()?;
+
+        thread::Builder::new()
+            .name(String::from("midi in"))
+            .spawn(move || {
+                let mut input = seq.input();
+                loop {
+                    let event = match input.event_input() {
+                        Ok(event) => event,
+                        Err(e) => match io::Error::from_raw_os_error(e.errno() as i32).kind() {
+                            io::ErrorKind::Interrupted | io::ErrorKind::WouldBlock => continue,
+                            // the queue overflowed, the next events are fine
+                            io::ErrorKind::StorageFull => {
+                                warn!("MIDI input fell behind, some events were lost");
+                                continue;
+                            }
+                            // the sequencer is gone, retrying would only spin
+                            _ => {
+                                error!("receive MIDI event, stopping MIDI input: {e}");
+                                return;
+                            }
+                        },
+                    };
+                    let now = Instant::now();
+
+                    let Some(message) = to_message(&event) else {
+                        continue;
+                    };
+                    if message != MidiMessage::Clock {
+                        debug!("MIDI {message:?}");
+                    }
+                    mapper.handle(message, |name, value| {
+                        control.send(Control::SetConstant(name, value))
+                    });
+                    if let Some(follower) = &mut follower {
+                        follower.handle(message, now);
+                    }
+                }
+            })
+            .context("spawn MIDI input thread")?;
+
+        info!("MIDI input on ALSA sequencer port {client}:{port}");
+        Ok((client, port))
+    }
+
+    /// Open a sequencer client sending MIDI clock following the audio output
+    ///
+    /// Returns the client and port numbers, e.g. to use with `aconnect`.
+    pub(crate) fn spawn_clock_output(sample_data: Arc<SampleData>) -> Result<(i32, i32)> {
+        let seq = Seq::open(None, Some(alsa::Direction::Playback), false)
+            .context("open ALSA sequencer")?;
+        seq.set_client_name(&CString::new("glicol-cli clock")?)?;
+        let port = seq
+            .create_simple_port(
+                &CString::new("clock out")?,
+                PortCap::READ | PortCap::SUBS_READ,
+                PortType::MIDI_GENERIC | PortType::APPLICATION,
+            )
+            .context("create MIDI clock port")?;
+        let client = seq.client_id()?;
+
+        thread::Builder::new()
+            .name(String::from("midi clock"))
+            .spawn(move || {
+                let mut generator = ClockGenerator::default();
+                loop {
+                    let bpm = f32::from_bits(sample_data.bpm.load(Ordering::Relaxed));
+                    let beats = sample_data.clock.beats_at(Instant::now(), bpm);
+                    generator.update(beats, |message| {
+                        let event_type = match message {
+                            MidiMessage::Start => EventType::Start,
+                            MidiMessage::Stop => EventType::Stop,
+                            MidiMessage::Continue => EventType::Continue,
+                            _ => EventType::Clock,
+                        };
+                        let data = EvQueueControl {
+                            queue: 0,
+                            value: (),
+                        };
+                        let mut event = Event::new(event_type, &data);
+                        event.set_source(port);
+                        event.set_subs();
+                        event.set_direct();
+                        if let Err(e) = seq.event_output_direct(&mut event) {
+                            warn!("send MIDI clock: {e}");
+                        }
+                    });
+                    thread::sleep(CLOCK_POLL_INTERVAL);
+                }
+            })
+            .context("spawn MIDI clock thread")?;
+
+        info!("MIDI clock output on ALSA sequencer port {client}:{port}");
+        Ok((client, port))
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::{spawn_midi_input, to_message};
+
+        use std::{
+            ffi::CString,
+            thread,
+            time::{Duration, Instant},
+        };
+
+        use alsa::seq::{
+            Addr, EvCtrl, EvNote, Event, EventType, PortCap, PortSubscribe, PortType, Seq,
+        };
+
+        use crate::{
+            control::{control_channel, Control},
+            midi::{MidiMapper, MidiMapping, MidiMessage},
+        };
+
+        #[test]
+        fn convert_sequencer_events() {
+            let ctrl = EvCtrl {
+                channel: 2,
+                param: 74,
+                value: 300,
+            };
+            assert_eq!(
+                to_message(&Event::new(EventType::Controller, &ctrl)),
+                Some(MidiMessage::ControlChange {
+                    channel: 2,
+                    controller: 74,
+                    value: 127
+                })
+            );
+            let note = EvNote {
+                channel: 0,
+                note: 69,
+                velocity: 100,
+                off_velocity: 0,
+                duration: 0,
+            };
+            assert_eq!(
+                to_message(&Event::new(EventType::Noteon, &note)),
+                Some(MidiMessage::NoteOn {
+                    channel: 0,
+                    note: 69,
+                    velocity: 100
+                })
+            );
+            assert_eq!(
+                to_message(&Event::new(EventType::Noteoff, &note)),
+                Some(MidiMessage::NoteOff {
+                    channel: 0,
+                    note: 69
+                })
+            );
+            assert_eq!(to_message(&Event::new(EventType::Pgmchange, &ctrl)), None);
+        }
+
+        #[test]
+        fn receive_from_virtual_port() {
+            // sandboxes and CI machines often have no sequencer
+            let Ok(seq) = Seq::open(None, Some(alsa::Direction::Playback), false) else {
+                eprintln!("skipped, the ALSA sequencer isn't available");
+                return;
+            };
+            let mapping = MidiMapping::parse("cc 7 ~amp 0 2").unwrap();
+            let (control, mut control_receiver) = control_channel();
+            let (client, port) = spawn_midi_input(MidiMapper::new(mapping), None, control).unwrap();
+
+            let source = seq
+                .create_simple_port(
+                    &CString::new("test out").unwrap(),
+                    PortCap::READ | PortCap::SUBS_READ,
+                    PortType::MIDI_GENERIC | PortType::APPLICATION,
+                )
+                .unwrap();
+            let subscription = PortSubscribe::empty().unwrap();
+            subscription.set_sender(Addr {
+                client: seq.client_id().unwrap(),
+                port: source,
+            });
+            subscription.set_dest(Addr { client, port });
+            seq.subscribe_port(&subscription).unwrap();
+
+            let ctrl = EvCtrl {
+                channel: 0,
+                param: 7,
+                value: 127,
+            };
+            let mut event = Event::new(EventType::Controller, &ctrl);
+            event.set_source(source);
+            event.set_subs();
+            event.set_direct();
+            seq.event_output_direct(&mut event).unwrap();
+
+            let deadline = Instant::now() + Duration::from_secs(10);
+            let received = loop {
+                match control_receiver.try_recv() {
+                    Some(control) => break control,
+                    None if Instant::now() < deadline => thread::sleep(Duration::from_millis(5)),
+                    None => panic!("no MIDI event received"),
+                }
+            };
+            assert_eq!(received, Control::SetConstant("~amp", 2.0));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{note_to_hz, MidiMapper, MidiMapping, MidiMessage};
+
+    #[test]
+    fn parse_mapping() {
+        let mapping = MidiMapping::parse(
+            "# comment\nchannel 2\ncc 74 ~cutoff 200 5000 # filter\n\ncc 1 ~amp\nnotes ~lead",
+        )
+        .unwrap();
+
+        assert_eq!(mapping.channel, Some(1));
+        assert_eq!(mapping.controllers[0].controller, 74);
+        assert_eq!(mapping.controllers[0].range, (200.0, 5000.0));
+        assert_eq!(mapping.controllers[1].constant, "~amp");
+        assert_eq!(mapping.controllers[1].range, (0.0, 1.0));
+        let notes = mapping.notes.unwrap();
+        assert_eq!((notes.pitch, notes.gate), ("~lead", "~lead_gate"));
+
+        let error = MidiMapping::parse("cc 1 ~a\ncc 200 ~b").unwrap_err();
+        assert_eq!(error.to_string(), "line 2: invalid CC number");
+        assert!(MidiMapping::parse("pitchbend ~a").is_err());
+        assert!(MidiMapping::parse("cc 1 ~a 0").is_err());
+    }
+
+    #[test]
+    fn map_controllers() {
+        let mapping = MidiMapping::parse("channel 1\ncc 74 ~cutoff 200 5000").unwrap();
+        let mut mapper = MidiMapper::new(mapping);
+        let mut set = vec![];
+
+        let cc = |channel, value| MidiMessage::ControlChange {
+            channel,
+            controller: 74,
+            value,
+        };
+        mapper.handle(cc(0, 127), |name, value| set.push((name, value)));
+        mapper.handle(cc(0, 0), |name, value| set.push((name, value)));
+        mapper.handle(cc(1, 64), |name, value| set.push((name, value)));
+
+        assert_eq!(set, [("~cutoff", 5000.0), ("~cutoff", 200.0)]);
+    }
+
+    #[test]
+    fn play_last_note_held() {
+        let mapping = MidiMapping::parse("notes ~lead").unwrap();
+        let mut mapper = MidiMapper::new(mapping);
+        let mut set = vec![];
+
+        let on = |note| MidiMessage::NoteOn {
+            channel: 3,
+            note,
+            velocity: 127,
+        };
+        let off = |note| MidiMessage::NoteOff { channel: 3, note };
+        for message in [on(69), on(81), off(81), off(69)] {
+            mapper.handle(message, |name, value| set.push((name, value)));
+        }
+
+        assert_eq!(
+            set,
+            [
+                ("~lead", 440.0),
+                ("~lead_gate", 1.0),
+                ("~lead", 880.0),
+                ("~lead_gate", 1.0),
+                ("~lead", 440.0),
+                ("~lead_gate", 0.0),
+            ]
+        );
+        assert_eq!(note_to_hz(57), 220.0);
+    }
+}
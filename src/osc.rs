@@ -0,0 +1,415 @@
+use std::{
+    net::{SocketAddr, UdpSocket},
+    sync::{atomic::Ordering, mpsc, Arc},
+    thread,
+};
+
+use anyhow::{bail, Context, Result};
+use tracing::{debug, info, warn};
+
+use crate::{
+    control::{Control, ControlSender},
+    record::RecordState,
+    tempo::clamp_bpm,
+    SampleData,
+};
+
+/// Largest UDP payload, enough for any code sent at once
+const MAX_PACKET_SIZE: usize = 65_507;
+
+/// Argument of an OSC message
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) enum OscArg {
+    Int(i32),
+    Long(i64),
+    Float(f32),
+    Double(f64),
+    Str(String),
+    Bool(bool),
+    /// Nil, impulse or blob, nothing we act on
+    Other,
+}
+
+impl OscArg {
+    fn as_f64(&self) -> Option<f64> {
+        match *self {
+            OscArg::Int(v) => Some(v as f64),
+            OscArg::Long(v) => Some(v as f64),
+            OscArg::Float(v) => Some(v as f64),
+            OscArg::Double(v) => Some(v),
+            _ => None,
+        }
+    }
+
+    fn as_bool(&self) -> Option<bool> {
+        match self {
+            OscArg::Bool(v) => Some(*v),
+            other => other.as_f64().map(|v| v != 0.0),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct OscMessage {
+    pub address: String,
+    pub args: Vec<OscArg>,
+}
+
+/// Cursor over an OSC packet, every field being padded to 4 bytes
+struct Reader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8]> {
+        let Some(bytes) = self.data.get(self.pos..self.pos + len) else {
+            bail!("truncated packet");
+        };
+        self.pos += len.next_multiple_of(4);
+        Ok(bytes)
+    }
+
+    fn read_u32(&mut self) -> Result<u32> {
+        Ok(u32::from_be_bytes(self.take(4)?.try_into()?))
+    }
+
+    fn read_u64(&mut self) -> Result<u64> {
+        Ok(u64::from_be_bytes(self.take(8)?.try_into()?))
+    }
+
+    fn read_str(&mut self) -> Result<&'a str> {
+        let rest = self.data.get(self.pos..).unwrap_or_default();
+        let len = rest
+            .iter()
+            .position(|b| *b == 0)
+            .context("unterminated string")?;
+        let bytes = self.take(len + 1)?;
+        std::str::from_utf8(&bytes[..len]).context("string isn't UTF-8")
+    }
+}
+
+/// Decode a packet, flattening bundles into their messages
+///
+/// Bundle time tags are ignored, everything applies as soon as it's received.
+pub(crate) fn decode_packet(packet: &[u8]) -> Result<Vec<OscMessage>> {
+    let mut messages = vec![];
+    decode_into(packet, &mut messages)?;
+    Ok(messages)
+}
+
+fn decode_into(packet: &[u8], messages: &mut Vec<OscMessage>) -> Result<()> {
+    let mut reader = Reader {
+        data: packet,
+        pos: 0,
+    };
+
+    let address = reader.read_str()?;
+    if address == "#bundle" {
+        reader.read_u64()?; // time tag
+        while reader.pos < packet.len() {
+            let size = reader.read_u32()? as usize;
+            decode_into(reader.take(size)?, messages)?;
+        }
+        return Ok(());
+    }
+    if !address.starts_with('/') {
+        bail!("invalid address {address:?}");
+    }
+
+    // messages without type tags are allowed by older clients
+    let tags = match reader.pos < packet.len() {
+        true => reader.read_str()?,
+        false => ",",
+    };
+    let Some(tags) = tags.strip_prefix(',') else {
+        bail!("invalid type tags {tags:?}");
+    };
+
+    let mut args = Vec::with_capacity(tags.len());
+    for tag in tags.chars() {
+        let arg = match tag {
+            'i' => OscArg::Int(reader.read_u32()? as i32),
+            'h' => OscArg::Long(reader.read_u64()? as i64),
+            'f' => OscArg::Float(f32::from_bits(reader.read_u32()?)),
+            'd' => OscArg::Double(f64::from_bits(reader.read_u64()?)),
+            's' | 'S' => OscArg::Str(reader.read_str()?.to_owned()),
+            'T' => OscArg::Bool(true),
+            'F' => OscArg::Bool(false),
+            'N' | 'I' => OscArg::Other,
+            'b' => {
+                let len = reader.read_u32()? as usize;
+                reader.take(len)?;
+                OscArg::Other
+            }
+            // other 32-bit types: char, color, MIDI message
+            'c' | 'r' | 'm' => {
+                reader.read_u32()?;
+                OscArg::Other
+            }
+            't' => {
+                reader.read_u64()?;
+                OscArg::Other
+            }
+            tag => bail!("unsupported type tag '{tag}'"),
+        };
+        args.push(arg);
+    }
+
+    messages.push(OscMessage {
+        address: address.to_owned(),
+        args,
+    });
+    Ok(())
+}
+
+/// What OSC messages act on
+pub(crate) struct OscTargets {
+    /// Same channel as the file watcher, code goes through the updater
+    pub code_updates: mpsc::Sender<String>,
+    pub control: ControlSender,
+    pub sample_data: Arc<SampleData>,
+    pub record_state: Option<Arc<RecordState>>,
+}
+
+/// Listen for OSC messages on `address`, returning the address actually bound
+///
+/// - `/glicol/code <string>` replaces the whole code
+/// - `/glicol/bpm <number>` sets the tempo
+/// - `/glicol/pause [bool]` pauses, resumes or toggles without argument
+/// - `/glicol/record [bool]` same for the recording, needs `--record`
+pub(crate) fn spawn_osc_server(address: SocketAddr, targets: OscTargets) -> Result<SocketAddr> {
+    let socket = UdpSocket::bind(address).with_context(|| format!("bind OSC socket {address}"))?;
+    let local_address = socket.local_addr()?;
+
+    thread::Builder::new()
+        .name(String::from("osc"))
+        .spawn(move || {
+            let mut buffer = vec![0; MAX_PACKET_SIZE];
+            loop {
+                let (len, from) = match socket.recv_from(&mut buffer) {
+                    Ok(received) => received,
+                    Err(e) => {
+                        warn!("receive OSC packet: {e}");
+                        continue;
+                    }
+                };
+
+                match decode_packet(&buffer[..len]) {
+                    Ok(messages) => {
+                        for message in messages {
+                            debug!("OSC from {from}: {message:?}");
+                            handle_message(message, &targets);
+                        }
+                    }
+                    Err(e) => warn!("invalid OSC packet from {from}: {e:#}"),
+                }
+            }
+        })
+        .context("spawn OSC thread")?;
+
+    info!("listening for OSC on {local_address}");
+    Ok(local_address)
+}
+
+fn handle_message(message: OscMessage, targets: &OscTargets) {
+    let first = message.args.into_iter().next();
+
+    match message.address.as_str() {
+        "/glicol/code" => match first {
+            Some(OscArg::Str(code)) => {
+                info!("code received over OSC");
+                // the updater only stops with the program
+                targets.code_updates.send(code).ok();
+            }
+            _ => warn!("/glicol/code expects the code as a string"),
+        },
+        "/glicol/bpm" => match first.as_ref().and_then(OscArg::as_f64) {
+            Some(bpm) if bpm.is_finite() => {
+                let bpm = clamp_bpm(bpm as f32);
+                info!("tempo set to {bpm} BPM over OSC");
+                targets.control.send(Control::SetBpm(bpm));
+            }
+            _ => warn!("/glicol/bpm expects a number"),
+        },
+        "/glicol/pause" => {
+            let paused = &targets.sample_data.paused;
+            match first.as_ref().and_then(OscArg::as_bool) {
+                Some(pause) => paused.store(pause, Ordering::Relaxed),
+                None => {
+                    paused.fetch_xor(true, Ordering::Relaxed);
+                }
+            }
+        }
+        "/glicol/record" => {
+            let Some(record_state) = &targets.record_state else {
+                warn!("/glicol/record needs glicol-cli to be started with --record");
+                return;
+            };
+            match first.as_ref().and_then(OscArg::as_bool) {
+                Some(record) => record_state.recording.store(record, Ordering::Relaxed),
+                None => record_state.toggle(),
+            }
+        }
+        address => warn!("unknown OSC address {address}"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{decode_packet, spawn_osc_server, OscArg, OscMessage, OscTargets};
+
+    use std::{
+        net::UdpSocket,
+        sync::{
+            atomic::{AtomicBool, AtomicU32, Ordering},
+            mpsc, Arc,
+        },
+        thread,
+        time::{Duration, Instant},
+    };
+
+    use crate::{
+        clock::ClockPosition,
+        control::{control_channel, Control},
+        master::MasterState,
+        meter::Levels,
+        recovery::DeviceStatus,
+        timing::CallbackStats,
+        SampleData,
+    };
+
+    /// Wait for the server to act on the messages, failing after a while
+    fn wait_for<T>(mut poll: impl FnMut() -> Option<T>) -> T {
+        let deadline = Instant::now() + Duration::from_secs(10);
+        loop {
+            if let Some(value) = poll() {
+                return value;
+            }
+            assert!(Instant::now() < deadline, "the OSC server took too long");
+            thread::sleep(Duration::from_millis(5));
+        }
+    }
+
+    fn pad(bytes: &mut Vec<u8>) {
+        bytes.resize(bytes.len().next_multiple_of(4), 0);
+    }
+
+    fn encode_str(bytes: &mut Vec<u8>, s: &str) {
+        bytes.extend_from_slice(s.as_bytes());
+        bytes.push(0);
+        pad(bytes);
+    }
+
+    fn encode(message: &OscMessage) -> Vec<u8> {
+        let mut bytes = vec![];
+        encode_str(&mut bytes, &message.address);
+
+        let mut tags = String::from(",");
+        let mut data = vec![];
+        for arg in &message.args {
+            match arg {
+                OscArg::Int(v) => {
+                    tags.push('i');
+                    data.extend_from_slice(&v.to_be_bytes());
+                }
+                OscArg::Float(v) => {
+                    tags.push('f');
+                    data.extend_from_slice(&v.to_be_bytes());
+                }
+                OscArg::Str(s) => {
+                    tags.push('s');
+                    encode_str(&mut data, s);
+                }
+                OscArg::Bool(v) => tags.push(if *v { 'T' } else { 'F' }),
+                other => unimplemented!("{other:?}"),
+            }
+        }
+        encode_str(&mut bytes, &tags);
+        bytes.extend(data);
+        bytes
+    }
+
+    fn bundle(packets: &[Vec<u8>]) -> Vec<u8> {
+        let mut bytes = vec![];
+        encode_str(&mut bytes, "#bundle");
+        bytes.extend_from_slice(&1u64.to_be_bytes()); // immediately
+        for packet in packets {
+            bytes.extend_from_slice(&(packet.len() as u32).to_be_bytes());
+            bytes.extend_from_slice(packet);
+        }
+        bytes
+    }
+
+    fn message(address: &str, args: Vec<OscArg>) -> OscMessage {
+        OscMessage {
+            address: address.to_owned(),
+            args,
+        }
+    }
+
+    #[test]
+    fn decode_messages_and_bundles() {
+        let code = message(
+            "/glicol/code",
+            vec![OscArg::Str(String::from("o: sin 440"))],
+        );
+        let bpm = message("/glicol/bpm", vec![OscArg::Float(90.5), OscArg::Int(-1)]);
+
+        assert_eq!(decode_packet(&encode(&code)).unwrap(), vec![code.clone()]);
+        assert_eq!(
+            decode_packet(&bundle(&[encode(&code), bundle(&[encode(&bpm)])])).unwrap(),
+            [code, bpm]
+        );
+
+        assert!(decode_packet(b"/glicol/code\0\0\0\0,s\0\0o: s").is_err());
+        assert!(decode_packet(b"glicol\0\0").is_err());
+    }
+
+    #[test]
+    fn act_on_messages_over_loopback() {
+        let (code_updates, code_receiver) = mpsc::channel();
+        let (control, mut control_receiver) = control_channel();
+        let sample_data = Arc::new(SampleData {
+            bpm: AtomicU32::new(120f32.to_bits()),
+            capacity: AtomicU32::new(0),
+            paused: AtomicBool::new(false),
+            clock: ClockPosition::new(),
+            levels: Levels::new(),
+            master: MasterState::new(0.0),
+            device: DeviceStatus::new("test"),
+            timing: CallbackStats::new(),
+        });
+
+        let targets = OscTargets {
+            code_updates,
+            control,
+            sample_data: sample_data.clone(),
+            record_state: None,
+        };
+        let address = spawn_osc_server("127.0.0.1:0".parse().unwrap(), targets).unwrap();
+
+        let client = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let send = |message: OscMessage| client.send_to(&encode(&message), address).unwrap();
+        send(message(
+            "/glicol/code",
+            vec![OscArg::Str(String::from("o: sin 220"))],
+        ));
+        send(message("/glicol/bpm", vec![OscArg::Int(140)]));
+        send(message("/glicol/pause", vec![]));
+        send(message("/glicol/record", vec![OscArg::Bool(true)]));
+
+        assert_eq!(
+            code_receiver.recv_timeout(Duration::from_secs(10)).unwrap(),
+            "o: sin 220"
+        );
+        assert_eq!(
+            wait_for(|| control_receiver.try_recv()),
+            Control::SetBpm(140.0)
+        );
+        wait_for(|| sample_data.paused.load(Ordering::Relaxed).then_some(()));
+
+        send(message("/glicol/pause", vec![OscArg::Int(0)]));
+        wait_for(|| (!sample_data.paused.load(Ordering::Relaxed)).then_some(()));
+    }
+}
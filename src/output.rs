@@ -0,0 +1,208 @@
+use anyhow::{bail, Result};
+use cpal::{
+    traits::DeviceTrait, BufferSize, SampleFormat, SampleRate, StreamConfig, SupportedBufferSize,
+    SupportedStreamConfigRange,
+};
+
+/// Output stream settings asked for on the command line, the device's defaults otherwise
+#[derive(Clone, Copy, Debug, Default)]
+pub(crate) struct OutputOptions {
+    pub sample_rate: Option<u32>,
+    pub buffer_size: Option<u32>,
+    pub channels: Option<u16>,
+}
+
+/// Pick the output config, checking the options against what the device supports
+pub(crate) fn output_config(
+    device: &cpal::Device,
+    options: OutputOptions,
+) -> Result<(StreamConfig, SampleFormat)> {
+    let default = device.default_output_config()?;
+    if options.sample_rate.is_none() && options.buffer_size.is_none() && options.channels.is_none()
+    {
+        // glicol plays stereo
+        let config = StreamConfig {
+            channels: 2,
+            sample_rate: default.sample_rate(),
+            buffer_size: BufferSize::Default,
+        };
+        return Ok((config, default.sample_format()));
+    }
+
+    let supported: Vec<_> = device.supported_output_configs()?.collect();
+    pick_config(
+        &supported,
+        default.sample_format(),
+        default.sample_rate().0,
+        options,
+    )
+}
+
+fn pick_config(
+    supported: &[SupportedStreamConfigRange],
+    default_format: SampleFormat,
+    default_rate: u32,
+    options: OutputOptions,
+) -> Result<(StreamConfig, SampleFormat)> {
+    let sample_rate = options.sample_rate.unwrap_or(default_rate);
+    let channels = options.channels.unwrap_or(2);
+
+    let with_channels: Vec<_> = supported
+        .iter()
+        .filter(|range| range.channels() == channels)
+        .collect();
+    if with_channels.is_empty() {
+        let mut counts: Vec<_> = supported.iter().map(|range| range.channels()).collect();
+        counts.sort_unstable();
+        counts.dedup();
+        bail!("the device doesn't support {channels} channels, only {counts:?}");
+    }
+
+    let with_rate: Vec<_> = with_channels
+        .into_iter()
+        .filter(|range| {
+            (range.min_sample_rate().0..=range.max_sample_rate().0).contains(&sample_rate)
+        })
+        .collect();
+    if with_rate.is_empty() {
+        let rates: Vec<_> = supported
+            .iter()
+            .filter(|range| range.channels() == channels)
+            .map(|range| {
+                format!(
+                    "{}-{} Hz",
+                    range.min_sample_rate().0,
+                    range.max_sample_rate().0
+                )
+            })
+            .collect();
+        bail!(
+            "the device doesn't support {sample_rate} Hz with {channels} channels, only {}",
+            rates.join(", ")
+        );
+    }
+
+    let with_buffer: Vec<_> = match options.buffer_size {
+        Some(frames) => with_rate
+            .iter()
+            .copied()
+            .filter(|range| match *range.buffer_size() {
+                SupportedBufferSize::Range { min, max } => (min..=max).contains(&frames),
+                // can't tell, the stream will fail to open if it's wrong
+                SupportedBufferSize::Unknown => true,
+            })
+            .collect(),
+        None => with_rate.clone(),
+    };
+    let Some(&range) = with_buffer
+        .iter()
+        .find(|range| range.sample_format() == default_format)
+        .or_else(|| {
+            with_buffer
+                .iter()
+                .find(|range| range.sample_format() == SampleFormat::F32)
+        })
+        .or(with_buffer.first())
+    else {
+        let sizes: Vec<_> = with_rate
+            .iter()
+            .filter_map(|range| match *range.buffer_size() {
+                SupportedBufferSize::Range { min, max } => Some(format!("{min}-{max}")),
+                SupportedBufferSize::Unknown => None,
+            })
+            .collect();
+        bail!(
+            "the device doesn't support buffers of {} frames at {sample_rate} Hz, only {}",
+            options.buffer_size.unwrap_or_default(),
+            sizes.join(", ")
+        );
+    };
+
+    let config = StreamConfig {
+        channels,
+        sample_rate: SampleRate(sample_rate),
+        buffer_size: match options.buffer_size {
+            Some(frames) => BufferSize::Fixed(frames),
+            None => BufferSize::Default,
+        },
+    };
+    Ok((config, range.sample_format()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{pick_config, OutputOptions};
+
+    use cpal::{
+        BufferSize, SampleFormat, SampleRate, SupportedBufferSize, SupportedStreamConfigRange,
+    };
+
+    fn range(channels: u16, format: SampleFormat) -> SupportedStreamConfigRange {
+        SupportedStreamConfigRange::new(
+            channels,
+            SampleRate(44100),
+            SampleRate(48000),
+            SupportedBufferSize::Range { min: 64, max: 1024 },
+            format,
+        )
+    }
+
+    #[test]
+    fn pick_supported_configs() {
+        let supported = [
+            range(2, SampleFormat::I16),
+            range(2, SampleFormat::F32),
+            range(4, SampleFormat::I32),
+        ];
+        let options = OutputOptions {
+            buffer_size: Some(256),
+            ..Default::default()
+        };
+
+        let (config, format) = pick_config(&supported, SampleFormat::I16, 48000, options).unwrap();
+        assert_eq!(config.channels, 2);
+        assert_eq!(config.sample_rate, SampleRate(48000));
+        assert_eq!(config.buffer_size, BufferSize::Fixed(256));
+        assert_eq!(format, SampleFormat::I16);
+
+        let options = OutputOptions {
+            channels: Some(4),
+            ..Default::default()
+        };
+        let (config, format) = pick_config(&supported, SampleFormat::I16, 44100, options).unwrap();
+        assert_eq!(config.buffer_size, BufferSize::Default);
+        assert_eq!(format, SampleFormat::I32);
+    }
+
+    #[test]
+    fn explain_unsupported_configs() {
+        let supported = [range(2, SampleFormat::F32)];
+        let error = |options| {
+            pick_config(&supported, SampleFormat::F32, 48000, options)
+                .unwrap_err()
+                .to_string()
+        };
+
+        assert_eq!(
+            error(OutputOptions {
+                channels: Some(6),
+                ..Default::default()
+            }),
+            "the device doesn't support 6 channels, only [2]"
+        );
+        assert_eq!(
+            error(OutputOptions {
+                sample_rate: Some(96000),
+                ..Default::default()
+            }),
+            "the device doesn't support 96000 Hz with 2 channels, only 44100-48000 Hz"
+        );
+        assert_eq!(
+            error(OutputOptions {
+                buffer_size: Some(4096),
+                ..Default::default()
+            }),
+            "the device doesn't support buffers of 4096 frames at 48000 Hz, only 64-1024"
+        );
+    }
+}
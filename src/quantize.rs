@@ -0,0 +1,160 @@
+/// Nodes playing patterns that start with the engine and last a bar
+const BAR_NODES: [&str; 5] = ["seq", "arrange", "psampler", "pattern_synth", "points"];
+
+/// When a code update starts playing
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum)]
+pub(crate) enum Quantize {
+    /// As soon as it's ready
+    None,
+    /// On the next beat, or the next bar for code with bar patterns
+    Beat,
+    /// On the next bar, assuming 4/4
+    Bar,
+}
+
+impl Quantize {
+    fn beats(self) -> Option<f64> {
+        match self {
+            Quantize::None => None,
+            Quantize::Beat => Some(1.0),
+            Quantize::Bar => Some(4.0),
+        }
+    }
+
+    /// Find a `// @quantize <none|beat|bar>` directive in the code
+    pub fn from_directive(code: &str) -> Option<Self> {
+        code.lines().find_map(|line| {
+            let comment = line.trim_start().strip_prefix("//")?;
+            let value = comment.trim_start().strip_prefix("@quantize")?;
+            clap::ValueEnum::from_str(value.trim(), true).ok()
+        })
+    }
+
+    /// The quantization `code` can use, keeping its patterns in phase with the bar
+    ///
+    /// A new engine plays its patterns from their first step, which is only right on a bar.
+    pub fn for_code(self, code: &str) -> Self {
+        match self {
+            Quantize::Beat if has_bar_patterns(code) => Quantize::Bar,
+            quantize => quantize,
+        }
+    }
+}
+
+fn has_bar_patterns(code: &str) -> bool {
+    code.lines()
+        .map(|line| line.split("//").next().unwrap_or_default())
+        .flat_map(|line| line.split(|c: char| !(c.is_alphanumeric() || c == '_' || c == '~')))
+        .any(|word| BAR_NODES.contains(&word))
+}
+
+/// Musical position of the audio output, in beats since the first update
+pub(crate) struct Transport {
+    sr: f64,
+    bpm: f64,
+    beats: f64,
+}
+
+impl Transport {
+    pub fn new(sr: usize, bpm: f32) -> Self {
+        Self {
+            sr: sr as f64,
+            bpm: bpm as f64,
+            beats: 0.0,
+        }
+    }
+
+    /// Restart from the first beat, played `offset` frames into the current buffer
+    ///
+    /// The position is right again once the whole buffer is advanced over.
+    pub fn start(&mut self, offset: usize) {
+        self.beats = 0.0;
+        self.rewind(offset);
+    }
+
+    fn rewind(&mut self, frames: usize) {
+        self.beats -= frames as f64 * self.bpm / 60.0 / self.sr;
+    }
+
+    /// Change the tempo from now on, keeping the current position
+    pub fn set_bpm(&mut self, bpm: f32) {
+        self.bpm = bpm as f64;
+    }
+
+    pub fn beats(&self) -> f64 {
+        self.beats
+    }
+
+    pub fn advance(&mut self, frames: usize) {
+        self.beats += frames as f64 * self.bpm / 60.0 / self.sr;
+    }
+
+    /// Frames left until the next boundary, 0 when right on one
+    pub fn frames_to_boundary(&self, quantize: Quantize) -> usize {
+        let Some(unit) = quantize.beats() else {
+            return 0;
+        };
+
+        // tolerate the rounding accumulated by `advance`
+        let position = self.beats / unit;
+        let next = (position - 1e-9).ceil();
+        ((next - position) * unit * 60.0 / self.bpm * self.sr).round() as usize
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Quantize, Transport};
+
+    #[test]
+    fn read_directive() {
+        let code = "~a: sin 440\n  // @quantize beat\no: ~a";
+        assert_eq!(Quantize::from_directive(code), Some(Quantize::Beat));
+        assert_eq!(
+            Quantize::from_directive("//@quantize NONE"),
+            Some(Quantize::None)
+        );
+        assert_eq!(Quantize::from_directive("// @quantize later"), None);
+        assert_eq!(Quantize::from_directive("o: sin 440"), None);
+    }
+
+    #[test]
+    fn keep_patterns_in_phase() {
+        let mut transport = Transport::new(48000, 120.0);
+        // on the third beat of the bar
+        transport.advance(2 * 24000 + 1000);
+
+        let drone = "~seq: sin 220 // seq 60\no: ~seq >> mul 0.5";
+        let quantize = Quantize::Beat.for_code(drone);
+        assert_eq!(transport.frames_to_boundary(quantize), 23000);
+
+        let pattern = "o: seq 60 _ 60 _ >> sp \\808";
+        let quantize = Quantize::Beat.for_code(pattern);
+        assert_eq!(quantize, Quantize::Bar);
+        assert_eq!(transport.frames_to_boundary(quantize), 47000);
+        assert_eq!(Quantize::None.for_code(pattern), Quantize::None);
+    }
+
+    #[test]
+    fn boundaries() {
+        // a beat is 24000 frames at 120 BPM
+        let mut transport = Transport::new(48000, 120.0);
+        assert_eq!(transport.frames_to_boundary(Quantize::Beat), 0);
+        assert_eq!(transport.frames_to_boundary(Quantize::Bar), 0);
+
+        transport.advance(1000);
+        assert_eq!(transport.frames_to_boundary(Quantize::None), 0);
+        assert_eq!(transport.frames_to_boundary(Quantize::Beat), 23000);
+        assert_eq!(transport.frames_to_boundary(Quantize::Bar), 95000);
+
+        for _ in 0..23 {
+            transport.advance(1000);
+        }
+        assert_eq!(transport.frames_to_boundary(Quantize::Beat), 0);
+        assert_eq!(transport.frames_to_boundary(Quantize::Bar), 72000);
+
+        // the rest of the bar is twice as fast
+        transport.set_bpm(240.0);
+        assert_eq!(transport.frames_to_boundary(Quantize::Bar), 36000);
+    }
+}
@@ -0,0 +1,283 @@
+use std::{
+    fs::File,
+    io::BufWriter,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
+        Arc,
+    },
+    thread::{self, JoinHandle},
+    time::Duration,
+};
+
+use anyhow::{Context, Result};
+use ringbuf::{HeapConsumer, HeapProducer, HeapRb};
+use tracing::{error, info, warn};
+
+use crate::wav::{WavFormat, WavSpec, WavWriter};
+
+/// Seconds of audio the ring buffer between the callback and the writer can hold
+const BUFFER_SECONDS: usize = 2;
+
+/// How often the writer thread drains the ring buffer
+const DRAIN_INTERVAL: Duration = Duration::from_millis(50);
+
+/// Recording state shared between the audio callback, the writer thread and the TUI
+pub(crate) struct RecordState {
+    /// Toggled by the user, the writer opens and closes takes accordingly
+    pub recording: AtomicBool,
+    /// Frames written to the current take
+    pub frames: AtomicU64,
+    /// Samples the callback couldn't push because the writer was late
+    pub dropped: AtomicUsize,
+    pub sample_rate: u32,
+    stop: AtomicBool,
+}
+
+impl RecordState {
+    pub fn toggle(&self) {
+        // from the TUI and OSC, toggling atomically doesn't lose either
+        self.recording.fetch_xor(true, Ordering::Relaxed);
+    }
+
+    /// Duration of the current take
+    pub fn elapsed(&self) -> Duration {
+        Duration::from_secs_f64(
+            self.frames.load(Ordering::Relaxed) as f64 / self.sample_rate as f64,
+        )
+    }
+}
+
+/// Audio callback side of the recorder
+pub(crate) struct RecordTap {
+    producer: HeapProducer<f32>,
+    state: Arc<RecordState>,
+}
+
+impl RecordTap {
+    pub fn is_recording(&self) -> bool {
+        self.state.recording.load(Ordering::Relaxed)
+    }
+
+    /// Queue an interleaved frame for the writer, never blocks
+    pub fn push_frame(&mut self, frame: &[f32]) {
+        let pushed = self.producer.push_slice(frame);
+        if pushed < frame.len() {
+            self.state
+                .dropped
+                .fetch_add(frame.len() - pushed, Ordering::Relaxed);
+        }
+    }
+}
+
+/// Handle on the writer thread, which saves the recorded takes to disk
+pub(crate) struct Recorder {
+    pub state: Arc<RecordState>,
+    thread: JoinHandle<()>,
+}
+
+impl Recorder {
+    /// Start the writer thread, recording right away to `path`
+    pub fn spawn(path: PathBuf, channels: u16, sample_rate: u32) -> Result<(Self, RecordTap)> {
+        let (mut takes, tap) = Takes::new(path, channels, sample_rate);
+        let state = takes.state.clone();
+        let thread = thread::Builder::new()
+            .name(String::from("recorder"))
+            .spawn(move || loop {
+                match takes.write_queued() {
+                    Ok(true) => thread::park_timeout(DRAIN_INTERVAL),
+                    Ok(false) => return,
+                    Err(e) => {
+                        error!("recording: {e:#}");
+                        return;
+                    }
+                }
+            })
+            .context("spawn recorder thread")?;
+
+        Ok((Self { state, thread }, tap))
+    }
+
+    /// Stop recording and wait for the current take to be saved
+    pub fn finish(self) {
+        self.state.stop.store(true, Ordering::Relaxed);
+        self.thread.thread().unpark();
+        if self.thread.join().is_err() {
+            error!("recorder thread panicked");
+        }
+    }
+}
+
+/// Path of the n-th take, the first one being the given path
+fn take_path(path: &Path, take: usize) -> PathBuf {
+    if take == 1 {
+        return path.to_path_buf();
+    }
+
+    let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+    let name = match path.extension() {
+        Some(ext) => format!("{stem}-{take}.{}", ext.to_string_lossy()),
+        None => format!("{stem}-{take}"),
+    };
+    path.with_file_name(name)
+}
+
+/// Writer side of the recorder, saving the queued frames to the takes
+struct Takes {
+    path: PathBuf,
+    spec: WavSpec,
+    consumer: HeapConsumer<f32>,
+    state: Arc<RecordState>,
+    take: usize,
+    writer: Option<(PathBuf, WavWriter<BufWriter<File>>)>,
+    scratch: Vec<f32>,
+}
+
+impl Takes {
+    fn new(path: PathBuf, channels: u16, sample_rate: u32) -> (Self, RecordTap) {
+        let (producer, consumer) =
+            HeapRb::new(BUFFER_SECONDS * sample_rate as usize * channels as usize).split();
+
+        let state = Arc::new(RecordState {
+            recording: AtomicBool::new(true),
+            frames: AtomicU64::new(0),
+            dropped: AtomicUsize::new(0),
+            sample_rate,
+            stop: AtomicBool::new(false),
+        });
+        let tap = RecordTap {
+            producer,
+            state: state.clone(),
+        };
+
+        let takes = Self {
+            path,
+            spec: WavSpec {
+                channels,
+                sample_rate,
+                format: WavFormat::F32,
+            },
+            scratch: vec![0.0; consumer.capacity()],
+            consumer,
+            state,
+            take: 0,
+            writer: None,
+        };
+        (takes, tap)
+    }
+
+    /// Write the queued frames, opening and closing takes as the state asks
+    ///
+    /// Returns false once the recorder is stopped and the last take saved
+    fn write_queued(&mut self) -> Result<bool> {
+        let stopping = self.state.stop.load(Ordering::Relaxed);
+        let recording = self.state.recording.load(Ordering::Relaxed);
+
+        if recording && self.writer.is_none() {
+            self.take += 1;
+            let path = take_path(&self.path, self.take);
+            let file = File::create(&path).with_context(|| format!("create {}", path.display()))?;
+            let wav =
+                WavWriter::new(BufWriter::new(file), self.spec).context("write WAV header")?;
+            self.state.frames.store(0, Ordering::Relaxed);
+            info!("recording to {}", path.display());
+            self.writer = Some((path, wav));
+        }
+
+        if let Some((_, wav)) = &mut self.writer {
+            let read = self.consumer.pop_slice(&mut self.scratch);
+            // keep whole frames only, the rest stays queued for the next round
+            let read = read - read % self.spec.channels as usize;
+            wav.write_samples(&self.scratch[..read])
+                .context("write samples")?;
+            wav.update_header().context("update WAV header")?;
+            self.state
+                .frames
+                .store(wav.frames() as u64, Ordering::Relaxed);
+        }
+
+        if !recording || stopping {
+            match self.writer.take() {
+                Some((path, wav)) => {
+                    wav.finalize().context("finalize WAV file")?;
+                    info!("saved recording to {}", path.display());
+                }
+                // leftovers pushed before the callback noticed the end of the take
+                None => {
+                    self.consumer.clear();
+                }
+            }
+        }
+
+        let dropped = self.state.dropped.swap(0, Ordering::Relaxed);
+        if dropped > 0 {
+            warn!("recorder couldn't keep up, dropped {dropped} samples");
+        }
+
+        Ok(!stopping)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{take_path, Recorder, Takes};
+
+    use std::{
+        fs,
+        path::{Path, PathBuf},
+        sync::atomic::Ordering,
+    };
+
+    use tempfile::TempDir;
+
+    #[test]
+    fn number_later_takes() {
+        let path = Path::new("/tmp/gig.wav");
+        assert_eq!(take_path(path, 1), PathBuf::from("/tmp/gig.wav"));
+        assert_eq!(take_path(path, 2), PathBuf::from("/tmp/gig-2.wav"));
+        assert_eq!(take_path(Path::new("gig"), 3), PathBuf::from("gig-3"));
+    }
+
+    #[test]
+    fn save_takes_on_toggle() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("take.wav");
+
+        let (mut takes, mut tap) = Takes::new(path.clone(), 2, 100);
+        let state = takes.state.clone();
+        assert!(tap.is_recording());
+        for _ in 0..10 {
+            tap.push_frame(&[0.5, -0.5]);
+        }
+        assert!(takes.write_queued().unwrap());
+        assert_eq!(state.frames.load(Ordering::Relaxed), 10);
+
+        state.toggle();
+        takes.write_queued().unwrap();
+        state.toggle();
+        takes.write_queued().unwrap();
+        tap.push_frame(&[0.5, -0.5]);
+        state.stop.store(true, Ordering::Relaxed);
+        assert!(!takes.write_queued().unwrap());
+
+        // header, fact chunk and stereo float frames
+        assert_eq!(fs::read(&path).unwrap().len(), 58 + 10 * 8);
+        assert_eq!(
+            fs::read(dir.path().join("take-2.wav")).unwrap().len(),
+            58 + 8
+        );
+    }
+
+    #[test]
+    fn save_take_on_finish() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("take.wav");
+
+        let (recorder, mut tap) = Recorder::spawn(path.clone(), 2, 100).unwrap();
+        for _ in 0..10 {
+            tap.push_frame(&[0.5, -0.5]);
+        }
+        recorder.finish();
+        assert_eq!(fs::read(&path).unwrap().len(), 58 + 10 * 8);
+    }
+}
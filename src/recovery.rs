@@ -0,0 +1,413 @@
+use std::{
+    sync::{
+        atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering},
+        mpsc::{self, RecvTimeoutError},
+        Arc, Mutex, PoisonError,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+use anyhow::{anyhow, Context, Result};
+use cpal::{
+    traits::{DeviceTrait, HostTrait, StreamTrait},
+    FromSample, SizedSample, StreamError,
+};
+use tracing::{error, info, warn};
+
+use crate::{input, Callback};
+
+/// How long the callback may stay silent before the stream is considered dead
+const STALL_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// How often the callback is checked on
+const CHECK_INTERVAL: Duration = Duration::from_millis(500);
+
+/// Time between two attempts to open the device again
+const RETRY_INTERVAL: Duration = Duration::from_secs(1);
+
+/// State of the output device, shown by the TUI
+pub(crate) struct DeviceStatus {
+    connected: AtomicBool,
+    /// Attempts to reopen the device since it was lost
+    attempts: AtomicU32,
+    /// Callbacks run, to notice a stream that stopped without an error
+    callbacks: AtomicU64,
+    name: Mutex<String>,
+    /// The input feeding `~input` is gone, it plays silence until it's back
+    input_lost: AtomicBool,
+}
+
+impl DeviceStatus {
+    pub fn new(name: &str) -> Self {
+        Self {
+            connected: AtomicBool::new(true),
+            attempts: AtomicU32::new(0),
+            callbacks: AtomicU64::new(0),
+            name: Mutex::new(name.to_owned()),
+            input_lost: AtomicBool::new(false),
+        }
+    }
+
+    /// Called by the audio callback
+    pub fn tick(&self) {
+        self.callbacks.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn connected(&self, name: &str) {
+        name.clone_into(&mut self.name.lock().expect("poisoned lock"));
+        self.attempts.store(0, Ordering::Relaxed);
+        self.connected.store(true, Ordering::Relaxed);
+    }
+
+    fn reconnecting(&self, attempt: u32) {
+        self.connected.store(false, Ordering::Relaxed);
+        self.attempts.store(attempt, Ordering::Relaxed);
+    }
+
+    /// Name of the device playing, or last played while it's lost
+    pub fn name(&self) -> String {
+        self.name.lock().expect("poisoned lock").clone()
+    }
+
+    fn set_input_lost(&self, lost: bool) {
+        self.input_lost.store(lost, Ordering::Relaxed);
+    }
+
+    pub fn input_lost(&self) -> bool {
+        self.input_lost.load(Ordering::Relaxed)
+    }
+
+    /// Attempts to reopen the device while it's lost
+    pub fn reconnect_attempts(&self) -> Option<u32> {
+        match self.connected.load(Ordering::Relaxed) {
+            true => None,
+            false => Some(self.attempts.load(Ordering::Relaxed)),
+        }
+    }
+}
+
+/// Build an output stream running the callback, its errors sent to `errors`
+pub(crate) fn open_stream<T>(
+    device: &cpal::Device,
+    config: &cpal::StreamConfig,
+    callback: &Arc<Mutex<Callback>>,
+    errors: &mpsc::Sender<StreamError>,
+) -> Result<cpal::Stream>
+where
+    T: SizedSample + FromSample<f32>,
+{
+    let callback = callback.clone();
+    let errors = errors.clone();
+    let stream = device.build_output_stream(
+        config,
+        move |data: &mut [T], info: &cpal::OutputCallbackInfo| match callback.try_lock() {
+            Ok(mut callback) => callback.process(data, info.timestamp().callback),
+            // only after a panic or while a reopened input is swapped in, the streams never overlap
+            Err(_) => data.fill(T::from_sample(0.0)),
+        },
+        move |err| {
+            let _ = errors.send(err);
+        },
+        None,
+    )?;
+    Ok(stream)
+}
+
+/// What's needed to open the stream again
+pub(crate) struct Reopen {
+    pub host_id: cpal::HostId,
+    /// Device tried first, the default one after it
+    pub name: String,
+    pub config: cpal::StreamConfig,
+    pub callback: Arc<Mutex<Callback>>,
+    pub error_sender: mpsc::Sender<StreamError>,
+    pub input: Option<ReopenInput>,
+}
+
+/// What's needed to open the input feeding `~input` again
+pub(crate) struct ReopenInput {
+    /// Device tried first, the default input after it
+    pub name: String,
+    pub error_sender: mpsc::Sender<StreamError>,
+}
+
+impl Reopen {
+    /// Open the same device if it's back, the default one otherwise
+    fn open<T>(&self) -> Result<(cpal::Stream, String)>
+    where
+        T: SizedSample + FromSample<f32>,
+    {
+        let host = cpal::host_from_id(self.host_id).context("open host")?;
+        let same = host
+            .output_devices()
+            .context("list output devices")?
+            .find(|device| device.name().is_ok_and(|name| name == self.name));
+        let fallback = host
+            .default_output_device()
+            .filter(|device| device.name().map_or(true, |name| name != self.name));
+
+        let mut last_error = None;
+        for device in same.into_iter().chain(fallback) {
+            let name = device.name().unwrap_or_default();
+            if name != self.name && !supports_config::<T>(&device, &self.config) {
+                last_error = Some(anyhow!(
+                    "fallback device '{name}' doesn't support {}",
+                    describe_config::<T>(&self.config)
+                ));
+                continue;
+            }
+            let stream =
+                open_stream::<T>(&device, &self.config, &self.callback, &self.error_sender)
+                    .and_then(|stream| Ok(stream.play().map(|_| stream)?));
+            match stream {
+                Ok(stream) => return Ok((stream, name)),
+                Err(e) => last_error = Some(e.context(format!("open '{name}'"))),
+            }
+        }
+        Err(last_error.unwrap_or_else(|| anyhow!("no output device")))
+    }
+
+    /// Open the same input device if it's back, the default one otherwise, and feed the
+    /// callback from it
+    fn open_input(&self, input: &ReopenInput) -> Result<(cpal::Stream, String)> {
+        let host = cpal::host_from_id(self.host_id).context("open host")?;
+        let same = host
+            .input_devices()
+            .context("list input devices")?
+            .find(|device| device.name().is_ok_and(|name| name == input.name));
+        let fallback = host
+            .default_input_device()
+            .filter(|device| device.name().map_or(true, |name| name != input.name));
+
+        let mut last_error = None;
+        for device in same.into_iter().chain(fallback) {
+            let name = device.name().unwrap_or_default();
+            let sample_rate = self.config.sample_rate;
+            let opened = input::input_config(&device, sample_rate).and_then(|config| {
+                input::run_input(&device, &config, sample_rate.0, &input.error_sender)
+            });
+            match opened {
+                Ok((stream, bridge)) => {
+                    let old = self
+                        .callback
+                        .lock()
+                        .unwrap_or_else(PoisonError::into_inner)
+                        .replace_input(bridge);
+                    // freed here rather than in the callback
+                    drop(old);
+                    return Ok((stream, name));
+                }
+                Err(e) => last_error = Some(e.context(format!("open input '{name}'"))),
+            }
+        }
+        Err(last_error.unwrap_or_else(|| anyhow!("no input device")))
+    }
+
+    /// Open the input again if there is one, reporting whether it's lost
+    fn reopen_input(&self, status: &DeviceStatus) -> Option<cpal::Stream> {
+        let input = self.input.as_ref()?;
+        match self.open_input(input) {
+            Ok((stream, name)) => {
+                if status.input_lost() {
+                    info!("audio input reconnected to '{name}'");
+                }
+                status.set_input_lost(false);
+                Some(stream)
+            }
+            Err(e) => {
+                if !status.input_lost() {
+                    error!("reopen audio input, `~input` stays silent until it's back: {e:#}");
+                }
+                status.set_input_lost(true);
+                None
+            }
+        }
+    }
+}
+
+/// Whether the device can play the config with `T` samples
+fn supports_config<T: SizedSample>(device: &cpal::Device, config: &cpal::StreamConfig) -> bool {
+    let Ok(mut ranges) = device.supported_output_configs() else {
+        return false;
+    };
+    ranges.any(|range| {
+        let buffer_size = match (config.buffer_size, range.buffer_size()) {
+            (cpal::BufferSize::Fixed(size), cpal::SupportedBufferSize::Range { min, max }) => {
+                (*min..=*max).contains(&size)
+            }
+            _ => true,
+        };
+        range.channels() == config.channels
+            && range.sample_format() == T::FORMAT
+            && (range.min_sample_rate()..=range.max_sample_rate()).contains(&config.sample_rate)
+            && buffer_size
+    })
+}
+
+fn describe_config<T: SizedSample>(config: &cpal::StreamConfig) -> String {
+    let buffer_size = match config.buffer_size {
+        cpal::BufferSize::Fixed(size) => format!(", {size} frame buffers"),
+        cpal::BufferSize::Default => String::new(),
+    };
+    format!(
+        "{} channels of {} at {} Hz{buffer_size}",
+        config.channels,
+        T::FORMAT,
+        config.sample_rate.0
+    )
+}
+
+/// Keep the streams playing, opening them again when a device is lost or the output stops
+/// calling back
+pub(crate) fn watch_stream<T>(
+    stream: cpal::Stream,
+    input_stream: Option<cpal::Stream>,
+    reopen: Reopen,
+    errors: mpsc::Receiver<StreamError>,
+    input_errors: mpsc::Receiver<StreamError>,
+    status: &DeviceStatus,
+) -> !
+where
+    T: SizedSample + FromSample<f32>,
+{
+    let mut stream = Some(stream);
+    let mut input_stream = input_stream;
+    let mut name = reopen.name.clone();
+    let mut last_callbacks = status.callbacks.load(Ordering::Relaxed);
+    let mut last_progress = Instant::now();
+    let mut last_input_attempt = Instant::now();
+    loop {
+        // the input can go away on its own, the output keeps playing meanwhile
+        while let Ok(err) = input_errors.try_recv() {
+            match err {
+                StreamError::DeviceNotAvailable => {
+                    warn!("audio input is no longer available");
+                    drop(input_stream.take());
+                    status.set_input_lost(true);
+                }
+                StreamError::BackendSpecific { err } => {
+                    error!("an error occurred on input stream: {err}");
+                }
+            }
+        }
+        if reopen.input.is_some()
+            && input_stream.is_none()
+            && last_input_attempt.elapsed() >= RETRY_INTERVAL
+        {
+            last_input_attempt = Instant::now();
+            input_stream = reopen.reopen_input(status);
+        }
+
+        match errors.recv_timeout(CHECK_INTERVAL) {
+            Ok(StreamError::DeviceNotAvailable) => {
+                warn!("audio device '{name}' is no longer available");
+            }
+            Ok(StreamError::BackendSpecific { err }) => {
+                // xruns and glitches the backend recovers from, unless the callbacks stop
+                error!("an error occurred on stream: {err}");
+                if !stalled(status, &mut last_callbacks, &mut last_progress) {
+                    continue;
+                }
+                warn!("audio stream on '{name}' stopped");
+            }
+            Err(RecvTimeoutError::Timeout) => {
+                if !stalled(status, &mut last_callbacks, &mut last_progress) {
+                    continue;
+                }
+                warn!("audio stream on '{name}' stopped");
+            }
+            Err(RecvTimeoutError::Disconnected) => unreachable!("the sender is kept in `reopen`"),
+        }
+
+        // the new stream takes over the callback once the old one is gone, the input usually
+        // goes away with it, like both sides of an audio interface
+        drop(stream.take());
+        drop(input_stream.take());
+        let mut last_failure = None;
+        for attempt in 1.. {
+            status.reconnecting(attempt);
+            match reopen.open::<T>() {
+                Ok((new_stream, new_name)) => {
+                    info!("audio reconnected to '{new_name}' after {attempt} attempts");
+                    status.connected(&new_name);
+                    stream = Some(new_stream);
+                    name = new_name;
+                    break;
+                }
+                Err(e) => {
+                    // the same failure every second would flood the log
+                    let failure = format!("{e:#}");
+                    if last_failure.as_ref() != Some(&failure) {
+                        error!("reconnect to audio device: {failure}");
+                        last_failure = Some(failure);
+                    }
+                }
+            }
+            thread::sleep(RETRY_INTERVAL);
+        }
+        // errors of the old streams
+        while errors.try_recv().is_ok() {}
+        while input_errors.try_recv().is_ok() {}
+        input_stream = reopen.reopen_input(status);
+        last_input_attempt = Instant::now();
+        last_callbacks = status.callbacks.load(Ordering::Relaxed);
+        last_progress = Instant::now();
+    }
+}
+
+/// Whether the callback hasn't run for `STALL_TIMEOUT`
+fn stalled(status: &DeviceStatus, last_callbacks: &mut u64, last_progress: &mut Instant) -> bool {
+    let callbacks = status.callbacks.load(Ordering::Relaxed);
+    if callbacks != *last_callbacks {
+        *last_callbacks = callbacks;
+        *last_progress = Instant::now();
+    }
+    last_progress.elapsed() > STALL_TIMEOUT
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{describe_config, stalled, DeviceStatus, STALL_TIMEOUT};
+
+    use std::time::Instant;
+
+    #[test]
+    fn describe_configs() {
+        let mut config = cpal::StreamConfig {
+            channels: 2,
+            sample_rate: cpal::SampleRate(48000),
+            buffer_size: cpal::BufferSize::Default,
+        };
+        assert_eq!(
+            describe_config::<f32>(&config),
+            "2 channels of f32 at 48000 Hz"
+        );
+        config.buffer_size = cpal::BufferSize::Fixed(256);
+        assert_eq!(
+            describe_config::<i16>(&config),
+            "2 channels of i16 at 48000 Hz, 256 frame buffers"
+        );
+    }
+
+    #[test]
+    fn notice_stalled_callbacks() {
+        let status = DeviceStatus::new("speakers");
+        let (mut last_callbacks, mut last_progress) = (0, Instant::now() - STALL_TIMEOUT * 2);
+
+        status.tick();
+        assert!(!stalled(&status, &mut last_callbacks, &mut last_progress));
+        last_progress -= STALL_TIMEOUT * 2;
+        assert!(stalled(&status, &mut last_callbacks, &mut last_progress));
+
+        status.reconnecting(3);
+        assert_eq!(status.reconnect_attempts(), Some(3));
+        status.connected("headphones");
+        assert_eq!(status.reconnect_attempts(), None);
+        assert_eq!(status.name(), "headphones");
+
+        assert!(!status.input_lost());
+        status.set_input_lost(true);
+        assert!(status.input_lost());
+    }
+}
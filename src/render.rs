@@ -0,0 +1,242 @@
+use std::{
+    fs::{self, File},
+    io::BufWriter,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{bail, Context, Result};
+use glicol::Engine;
+use tracing::info;
+
+use crate::{
+    decode_engine_error, samples, tempo,
+    tracks::{track_names, TrackSwitches},
+    wav::{WavFormat, WavSpec, WavWriter},
+    BLOCK_SIZE,
+};
+
+/// Render a .glicol file to a WAV file, faster than real time and without an audio device
+#[derive(clap::Args, Debug)]
+pub(crate) struct RenderArgs {
+    /// path to the .glicol file
+    #[arg(index = 1)]
+    file: PathBuf,
+
+    /// path of the WAV file to write
+    #[arg(short, long)]
+    output: PathBuf,
+
+    /// Length of the render in seconds
+    #[arg(
+        long,
+        conflicts_with = "bars",
+        required_unless_present = "bars",
+        allow_negative_numbers = true,
+        value_parser = parse_length
+    )]
+    duration: Option<f64>,
+
+    /// Length of the render in bars, at the given BPM
+    #[arg(long, allow_negative_numbers = true, value_parser = parse_length)]
+    bars: Option<f64>,
+
+    /// Set beats per minute (BPM)
+    #[arg(short, long, default_value_t = 120.0, allow_negative_numbers = true, value_parser = tempo::parse_bpm)]
+    bpm: f32,
+
+    /// Sample rate of the rendered file
+    #[arg(short = 'r', long, default_value_t = 44100)]
+    sample_rate: u32,
+
+    /// Sample format of the rendered file
+    #[arg(short, long, value_enum, default_value_t = WavFormat::F32)]
+    format: WavFormat,
+
+    /// Also render each `~name` chain alone to `<name>.wav`, next to the output
+    #[arg(long)]
+    stems: bool,
+}
+
+/// Check a length in seconds or bars given on the command line
+fn parse_length(arg: &str) -> Result<f64, String> {
+    let length: f64 = arg.parse().map_err(|e| format!("{e}"))?;
+    if !(length.is_finite() && length > 0.0) {
+        return Err(String::from("must be a positive number"));
+    }
+    Ok(length)
+}
+
+impl RenderArgs {
+    fn spec(&self) -> WavSpec {
+        WavSpec {
+            channels: 2,
+            sample_rate: self.sample_rate,
+            format: self.format,
+        }
+    }
+
+    /// Number of frames to render, as long as they fit in a WAV file
+    fn frames(&self) -> Result<usize> {
+        let seconds = match (self.duration, self.bars) {
+            (Some(duration), _) => duration,
+            (None, Some(bars)) => bars * 240.0 / self.bpm as f64,
+            (None, None) => unreachable!("enforced by clap"),
+        };
+
+        let frames = (seconds * self.sample_rate as f64).round();
+        let max_frames = self.spec().max_frames();
+        if frames > max_frames as f64 {
+            bail!(
+                "{seconds:.0}s don't fit in a WAV file, at most {:.0}s in {:?} at {} Hz",
+                max_frames as f64 / self.sample_rate as f64,
+                self.format,
+                self.sample_rate
+            );
+        }
+        Ok(frames as usize)
+    }
+}
+
+pub(crate) fn render(args: RenderArgs) -> Result<()> {
+    let frames = args.frames()?;
+    let code = fs::read_to_string(&args.file).context("read code")?;
+    // samples are leaked, so the mix and every stem can share them
+    let mut template = Engine::<BLOCK_SIZE>::new();
+    samples::load_samples_from_env(&mut template);
+
+    render_code(&template, &code, &args, frames, &args.output)?;
+    if !args.stems {
+        return Ok(());
+    }
+
+    let names = track_names(&code);
+    if names.is_empty() {
+        bail!("no ~name chains to render stems of");
+    }
+    let directory = args.output.parent().unwrap_or(Path::new(""));
+    for name in names {
+        let path = directory.join(format!("{}.wav", &name[1..]));
+        if path == args.output {
+            bail!("stem of {name} would overwrite {}", args.output.display());
+        }
+        // same as a solo in the TUI, keeping the tracks it uses
+        let mut switches = TrackSwitches::default();
+        switches.toggle_solo(name);
+        render_code(&template, &switches.apply(&code), &args, frames, &path)
+            .with_context(|| format!("render stem of {name}"))?;
+    }
+    Ok(())
+}
+
+/// Render the code from its start with the samples known by `template`, every file of a render
+/// getting the same length
+fn render_code(
+    template: &Engine<BLOCK_SIZE>,
+    code: &str,
+    args: &RenderArgs,
+    frames: usize,
+    output: &Path,
+) -> Result<()> {
+    let mut engine = Engine::<BLOCK_SIZE>::new();
+    engine.samples_dict.clone_from(&template.samples_dict);
+
+    engine.set_sr(args.sample_rate as usize);
+    engine.set_bpm(args.bpm);
+    // apply the code on the first block instead of waiting for a bar boundary
+    engine.livecoding = false;
+    engine.update_with_code(code);
+
+    let file = File::create(output).context("create output file")?;
+    let mut writer =
+        WavWriter::new(BufWriter::new(file), args.spec()).context("write WAV header")?;
+
+    let mut interleaved = [0.0; BLOCK_SIZE * 2];
+    let mut written = 0;
+    while written < frames {
+        let (block, raw_err) = engine.next_block(vec![]);
+        if let Some(msg) = decode_engine_error(&raw_err) {
+            bail!("get next block of engine: {msg}");
+        }
+
+        let len = BLOCK_SIZE.min(frames - written);
+        for i in 0..len {
+            interleaved[i * 2] = block[0][i];
+            interleaved[i * 2 + 1] = block[1][i];
+        }
+        writer
+            .write_samples(&interleaved[..len * 2])
+            .context("write samples")?;
+        written += len;
+    }
+
+    writer.finalize().context("finalize WAV file")?;
+    info!(
+        "rendered {frames} frames ({:.2}s) to {}",
+        frames as f64 / args.sample_rate as f64,
+        output.display()
+    );
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_length, render, RenderArgs};
+
+    use std::fs;
+
+    use tempfile::TempDir;
+
+    use crate::wav::WavFormat;
+
+    fn args(dir: &TempDir, code: &str) -> RenderArgs {
+        let file = dir.path().join("song.glicol");
+        fs::write(&file, code).unwrap();
+        RenderArgs {
+            file,
+            output: dir.path().join("song.wav"),
+            duration: None,
+            bars: Some(1.0),
+            bpm: 120.0,
+            sample_rate: 44100,
+            format: WavFormat::I16,
+            stems: false,
+        }
+    }
+
+    #[test]
+    fn render_mix_and_stems() {
+        let dir = TempDir::new().unwrap();
+        let mut args = args(&dir, "~a: sin 440\n~b: sin 220\nout: mix ~a ~b >> mul 0.5");
+        args.stems = true;
+        assert_eq!(args.frames().unwrap(), 88200);
+        render(args).unwrap();
+
+        // a bar at 120 BPM, 16-bit stereo
+        for name in ["song", "a", "b"] {
+            let bytes = fs::read(dir.path().join(format!("{name}.wav"))).unwrap();
+            assert_eq!(bytes.len(), 44 + 88200 * 4, "{name}");
+            assert!(bytes[44..].iter().any(|b| *b != 0), "{name} is silent");
+        }
+    }
+
+    #[test]
+    fn reject_lengths() {
+        assert_eq!(parse_length("2.5"), Ok(2.5));
+        for length in ["0", "-1", "NaN", "inf", "long"] {
+            assert!(parse_length(length).is_err(), "{length}");
+        }
+
+        let dir = TempDir::new().unwrap();
+        let mut args = args(&dir, "o: sin 440");
+        args.bars = None;
+        args.duration = Some(1e6);
+        assert!(args.frames().is_err());
+        args.format = WavFormat::F32;
+        // 4 GiB of 32-bit stereo at 44.1 kHz last about 12174s
+        args.duration = Some(12_200.0);
+        assert!(args.frames().is_err());
+        args.duration = Some(12_150.0);
+        assert!(args.frames().is_ok());
+    }
+}
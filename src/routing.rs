@@ -0,0 +1,189 @@
+use std::sync::Arc;
+
+use anyhow::{bail, Result};
+
+/// Audio sent to a device channel, as given on the command line
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) enum Source {
+    /// Left channel of the mix of the `out`-style chains
+    Left,
+    Right,
+    /// Both channels of the mix, averaged
+    Mono,
+    /// Output of a single chain, one of its channels or both averaged
+    Chain {
+        name: String,
+        channel: Option<usize>,
+    },
+}
+
+/// Parse a `--route` argument like `3=left`, `1=mono` or `4=rear.right`, channels counting from 1
+pub(crate) fn parse_route(arg: &str) -> Result<(u16, Source), String> {
+    let (channel, source) = arg
+        .split_once('=')
+        .ok_or_else(|| String::from("expected <CHANNEL>=<SOURCE>, e.g. 3=rear.left"))?;
+    let channel: u16 = channel.trim().parse().map_err(|e| format!("{e}"))?;
+    if channel == 0 {
+        return Err(String::from("channels count from 1"));
+    }
+
+    let source = match source.trim() {
+        "left" => Source::Left,
+        "right" => Source::Right,
+        "mono" => Source::Mono,
+        chain => {
+            let (name, channel) = match chain.rsplit_once('.') {
+                Some((name, "left")) => (name, Some(0)),
+                Some((name, "right")) => (name, Some(1)),
+                Some((_, suffix)) => return Err(format!("unknown channel '{suffix}'")),
+                None => (chain, None),
+            };
+            if name.is_empty() {
+                return Err(String::from("missing chain name"));
+            }
+            Source::Chain {
+                name: name.to_owned(),
+                channel,
+            }
+        }
+    };
+    Ok((channel, source))
+}
+
+/// Source of a device channel, with chains looked up by index
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Route {
+    Mix(usize),
+    MixMono,
+    Chain(usize, Option<usize>),
+}
+
+/// Which audio each channel of the device plays, silence for the channels without any
+pub(crate) struct Routing {
+    routes: Vec<Option<Route>>,
+    /// Chains played on their own, looked up in the engines as they change
+    chains: Arc<[String]>,
+}
+
+impl Routing {
+    /// The mix on the first two channels, or mixed down to mono for a single one
+    fn stereo(channels: u16) -> Self {
+        let mut routes = vec![None; channels as usize];
+        match channels {
+            1 => routes[0] = Some(Route::MixMono),
+            _ => {
+                routes[0] = Some(Route::Mix(0));
+                routes[1] = Some(Route::Mix(1));
+            }
+        }
+        Self {
+            routes,
+            chains: Arc::new([]),
+        }
+    }
+
+    pub fn new(sources: &[(u16, Source)], channels: u16) -> Result<Self> {
+        if sources.is_empty() {
+            return Ok(Self::stereo(channels));
+        }
+
+        let mut routes = vec![None; channels as usize];
+        let mut chains: Vec<String> = vec![];
+        for (channel, source) in sources {
+            let Some(slot) = routes.get_mut(*channel as usize - 1) else {
+                bail!("can't route to channel {channel}, the output has {channels} channels");
+            };
+            if slot.is_some() {
+                bail!("channel {channel} is routed twice");
+            }
+            *slot = Some(match source {
+                Source::Left => Route::Mix(0),
+                Source::Right => Route::Mix(1),
+                Source::Mono => Route::MixMono,
+                Source::Chain { name, channel } => {
+                    let index = match chains.iter().position(|chain| chain == name) {
+                        Some(index) => index,
+                        None => {
+                            chains.push(name.clone());
+                            chains.len() - 1
+                        }
+                    };
+                    Route::Chain(index, *channel)
+                }
+            });
+        }
+        Ok(Self {
+            routes,
+            chains: chains.into(),
+        })
+    }
+
+    pub fn chains(&self) -> Arc<[String]> {
+        self.chains.clone()
+    }
+
+    /// Sample of a device channel, from the mix and the frames of `chains()`
+    pub fn sample(&self, channel: usize, mix: [f32; 2], chains: &[[f32; 2]]) -> f32 {
+        match self.routes[channel] {
+            None => 0.0,
+            Some(Route::Mix(channel)) => mix[channel],
+            Some(Route::MixMono) => (mix[0] + mix[1]) * 0.5,
+            Some(Route::Chain(index, Some(channel))) => chains[index][channel],
+            Some(Route::Chain(index, None)) => (chains[index][0] + chains[index][1]) * 0.5,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_route, Routing, Source};
+
+    #[test]
+    fn parse_routes() {
+        assert_eq!(parse_route("1=left"), Ok((1, Source::Left)));
+        assert_eq!(
+            parse_route("4=rear.right"),
+            Ok((
+                4,
+                Source::Chain {
+                    name: String::from("rear"),
+                    channel: Some(1)
+                }
+            ))
+        );
+        assert_eq!(
+            parse_route("3=~pad"),
+            Ok((
+                3,
+                Source::Chain {
+                    name: String::from("~pad"),
+                    channel: None
+                }
+            ))
+        );
+        assert!(parse_route("0=left").is_err());
+        assert!(parse_route("2=rear.middle").is_err());
+        assert!(parse_route("left").is_err());
+    }
+
+    #[test]
+    fn route_mix_and_chains() {
+        let stereo = Routing::stereo(4);
+        let mix = [0.5, 0.25];
+        let samples: Vec<_> = (0..4).map(|c| stereo.sample(c, mix, &[])).collect();
+        assert_eq!(samples, [0.5, 0.25, 0.0, 0.0]);
+        assert_eq!(Routing::stereo(1).sample(0, mix, &[]), 0.375);
+
+        let routes =
+            ["1=mono", "3=rear.left", "4=rear.right", "5=~pad"].map(|r| parse_route(r).unwrap());
+        let routing = Routing::new(&routes, 6).unwrap();
+        assert_eq!(&*routing.chains(), ["rear", "~pad"]);
+        let chains = [[0.1, 0.2], [0.4, 0.0]];
+        let samples: Vec<_> = (0..6).map(|c| routing.sample(c, mix, &chains)).collect();
+        assert_eq!(samples, [0.375, 0.0, 0.1, 0.2, 0.2, 0.0]);
+
+        assert!(Routing::new(&routes, 4).is_err());
+        let twice = ["1=left", "1=right"].map(|r| parse_route(r).unwrap());
+        assert!(Routing::new(&twice, 2).is_err());
+    }
+}
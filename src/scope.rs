@@ -0,0 +1,193 @@
+use ringbuf::{HeapConsumer, HeapProducer, HeapRb};
+
+use crate::BLOCK_SIZE;
+
+/// Create both ends of a scope showing the last `length` frames played at `sample_rate`
+///
+/// It keeps at least `capture` frames, for the views needing more than the scope shows, and
+/// twice `length` for the trigger to look back a whole window.
+pub(crate) fn scope(length: usize, capture: usize, sample_rate: u32) -> (ScopeTap, Scope) {
+    let capture = capture.max(length * 2);
+    // room for the callbacks happening between two redraws of the TUI
+    let (producer, consumer) = HeapRb::new(capture + sample_rate as usize / 4).split();
+
+    let tap = ScopeTap {
+        producer,
+        staged: [[0.0; 2]; BLOCK_SIZE],
+        len: 0,
+    };
+    let scope = Scope {
+        consumer,
+        length,
+        history: vec![[0.0; 2]; capture],
+        start: 0,
+    };
+
+    (tap, scope)
+}
+
+/// Audio callback side of the scope
+///
+/// Frames are staged and pushed a block at a time, those that don't fit are dropped.
+pub(crate) struct ScopeTap {
+    producer: HeapProducer<[f32; 2]>,
+    staged: [[f32; 2]; BLOCK_SIZE],
+    len: usize,
+}
+
+impl ScopeTap {
+    pub fn push_frame(&mut self, frame: [f32; 2]) {
+        self.staged[self.len] = frame;
+        self.len += 1;
+        if self.len == BLOCK_SIZE {
+            self.flush();
+        }
+    }
+
+    /// Push the staged frames, called at the end of each callback
+    pub fn flush(&mut self) {
+        self.producer.push_slice(&self.staged[..self.len]);
+        self.len = 0;
+    }
+}
+
+/// TUI side of the scope, keeping the last frames played
+pub(crate) struct Scope {
+    consumer: HeapConsumer<[f32; 2]>,
+    length: usize,
+    /// Circular history, the oldest frame being at `start`
+    history: Vec<[f32; 2]>,
+    start: usize,
+}
+
+impl Scope {
+    /// Number of frames shown by default
+    pub fn len(&self) -> usize {
+        self.length
+    }
+
+    /// Number of frames kept
+    pub fn capacity(&self) -> usize {
+        self.history.len()
+    }
+
+    /// Take in the frames played since the last call
+    pub fn update(&mut self) {
+        let length = self.history.len();
+        // older frames would be overwritten anyway
+        let skipped = self.consumer.len().saturating_sub(length);
+        self.consumer.skip(skipped);
+
+        for frame in self.consumer.pop_iter() {
+            self.history[self.start] = frame;
+            self.start = (self.start + 1) % length;
+        }
+    }
+
+    /// Last `count` frames captured, up to the capture size, from the oldest
+    pub fn recent(&self, count: usize) -> impl Iterator<Item = [f32; 2]> + '_ {
+        let (newer, older) = self.history.split_at(self.start);
+        let skipped = self.history.len().saturating_sub(count);
+        older.iter().chain(newer).skip(skipped).copied()
+    }
+
+    /// `count` frames starting on the latest rising edge of the left channel, so periodic
+    /// waveforms stand still, or the most recent ones when there is no edge
+    pub fn triggered(&self, count: usize) -> impl Iterator<Item = [f32; 2]> + '_ {
+        let count = count.min(self.history.len());
+        let latest = self.history.len() - count;
+        // look back up to a whole window for the edge
+        let first = latest.saturating_sub(count).max(1);
+        let offset = (first..=latest)
+            .rev()
+            .find(|i| self.frame(i - 1)[0] <= 0.0 && self.frame(*i)[0] > 0.0)
+            .unwrap_or(latest);
+        (offset..offset + count).map(|i| self.frame(i))
+    }
+
+    /// Frame at `index`, counted from the oldest
+    fn frame(&self, index: usize) -> [f32; 2] {
+        self.history[(self.start + index) % self.history.len()]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::scope;
+
+    use crate::BLOCK_SIZE;
+
+    #[test]
+    fn push_whole_blocks() {
+        let (mut tap, mut scope) = scope(4, 4, 48000);
+
+        for i in 0..BLOCK_SIZE - 1 {
+            tap.push_frame([i as f32, 0.0]);
+        }
+        scope.update();
+        assert!(scope.recent(4).all(|frame| frame == [0.0; 2]));
+
+        tap.push_frame([-1.0, 1.0]);
+        scope.update();
+        let last = BLOCK_SIZE as f32 - 2.0;
+        assert_eq!(
+            scope.recent(4).collect::<Vec<_>>(),
+            [
+                [last - 2.0, 0.0],
+                [last - 1.0, 0.0],
+                [last, 0.0],
+                [-1.0, 1.0]
+            ]
+        );
+    }
+
+    #[test]
+    fn keep_last_frames_in_order() {
+        let (mut tap, mut scope) = scope(3, 4, 100);
+        assert_eq!(scope.capacity(), 6);
+
+        for i in 0..5 {
+            tap.push_frame([i as f32, -i as f32]);
+        }
+        tap.flush();
+        scope.update();
+        assert_eq!(scope.len(), 3);
+        assert_eq!(
+            scope.recent(3).map(|frame| frame[0]).collect::<Vec<_>>(),
+            [2.0, 3.0, 4.0]
+        );
+
+        tap.push_frame([5.0, -5.0]);
+        tap.flush();
+        scope.update();
+        assert_eq!(
+            scope.recent(3).map(|frame| frame[1]).collect::<Vec<_>>(),
+            [-3.0, -4.0, -5.0]
+        );
+        assert_eq!(
+            scope.recent(10).map(|frame| frame[0]).collect::<Vec<_>>(),
+            [0.0, 1.0, 2.0, 3.0, 4.0, 5.0]
+        );
+    }
+
+    #[test]
+    fn start_on_rising_edges() {
+        let (mut tap, mut scope) = scope(4, 8, 48000);
+        for sample in [0.0, 1.0, -1.0, -0.5, 0.5, 1.0, -1.0, 0.0] {
+            tap.push_frame([sample, 0.0]);
+        }
+        tap.flush();
+        scope.update();
+
+        let left = |frames: &mut dyn Iterator<Item = [f32; 2]>| {
+            frames.map(|frame| frame[0]).collect::<Vec<_>>()
+        };
+        assert_eq!(left(&mut scope.triggered(4)), [0.5, 1.0, -1.0, 0.0]);
+        assert_eq!(left(&mut scope.triggered(3)), [0.5, 1.0, -1.0]);
+        // no room to look back, free running
+        assert_eq!(
+            left(&mut scope.triggered(8)),
+            [0.0, 1.0, -1.0, -0.5, 0.5, 1.0, -1.0, 0.0]
+        );
+    }
+}
@@ -0,0 +1,221 @@
+use std::{collections::VecDeque, f32::consts::PI};
+
+/// Quietest level shown, in dB relative to a full scale sine
+pub(crate) const FLOOR_DB: f32 = -90.0;
+
+/// Lowest frequency shown, in Hz
+pub(crate) const MIN_HZ: f32 = 20.0;
+
+/// Spectra kept by the spectrogram, more than any terminal is wide
+const SPECTROGRAM_LENGTH: usize = 512;
+
+/// Check the FFT size given on the command line
+pub(crate) fn parse_fft_size(arg: &str) -> Result<usize, String> {
+    let size: usize = arg.parse().map_err(|e| format!("{e}"))?;
+    if !size.is_power_of_two() || !(256..=16384).contains(&size) {
+        return Err(String::from("must be a power of two between 256 and 16384"));
+    }
+    Ok(size)
+}
+
+/// Levels of the frequencies played, from a Hann windowed FFT
+pub(crate) struct Analyzer {
+    sample_rate: f32,
+    window: Vec<f32>,
+    /// `e^(-2πik/size)` for the first half of the bins
+    twiddles: Vec<(f32, f32)>,
+    re: Vec<f32>,
+    im: Vec<f32>,
+}
+
+impl Analyzer {
+    /// `size` must be a power of two
+    pub fn new(size: usize, sample_rate: u32) -> Self {
+        let window = (0..size)
+            .map(|i| 0.5 - 0.5 * (2.0 * PI * i as f32 / size as f32).cos())
+            .collect();
+        let twiddles = (0..size / 2)
+            .map(|k| {
+                let (sin, cos) = (-2.0 * PI * k as f32 / size as f32).sin_cos();
+                (cos, sin)
+            })
+            .collect();
+        Self {
+            sample_rate: sample_rate as f32,
+            window,
+            twiddles,
+            re: vec![0.0; size],
+            im: vec![0.0; size],
+        }
+    }
+
+    /// Number of samples analyzed at once
+    pub fn size(&self) -> usize {
+        self.window.len()
+    }
+
+    /// Width of the frequency bins, in Hz
+    pub fn bin_hz(&self) -> f32 {
+        self.sample_rate / self.size() as f32
+    }
+
+    /// Level of each bin from 0 Hz to Nyquist, in dB, of the last `size` samples
+    pub fn analyze(&mut self, samples: impl Iterator<Item = f32>) -> Vec<f32> {
+        self.re.fill(0.0);
+        self.im.fill(0.0);
+        for ((re, sample), window) in self.re.iter_mut().zip(samples).zip(&self.window) {
+            *re = sample * window;
+        }
+        fft(&mut self.re, &mut self.im, &self.twiddles);
+
+        // a full scale sine shows at 0 dB
+        let gain = 2.0 / self.window.iter().sum::<f32>();
+        (0..=self.size() / 2)
+            .map(|bin| {
+                let amplitude = self.re[bin].hypot(self.im[bin]) * gain;
+                (20.0 * amplitude.log10()).max(FLOOR_DB)
+            })
+            .collect()
+    }
+}
+
+/// In-place radix-2 FFT
+fn fft(re: &mut [f32], im: &mut [f32], twiddles: &[(f32, f32)]) {
+    let size = re.len();
+
+    let mut j = 0;
+    for i in 1..size {
+        let mut bit = size >> 1;
+        while j & bit != 0 {
+            j ^= bit;
+            bit >>= 1;
+        }
+        j |= bit;
+        if i < j {
+            re.swap(i, j);
+            im.swap(i, j);
+        }
+    }
+
+    let mut len = 2;
+    while len <= size {
+        let half = len / 2;
+        let step = size / len;
+        for start in (0..size).step_by(len) {
+            for k in 0..half {
+                let (cos, sin) = twiddles[k * step];
+                let (a, b) = (start + k, start + k + half);
+                let t_re = re[b] * cos - im[b] * sin;
+                let t_im = re[b] * sin + im[b] * cos;
+                re[b] = re[a] - t_re;
+                im[b] = im[a] - t_im;
+                re[a] += t_re;
+                im[a] += t_im;
+            }
+        }
+        len <<= 1;
+    }
+}
+
+/// Group bin levels into `count` bands evenly spread on a log frequency axis
+///
+/// Returns the center frequency and loudest level of each band, from `MIN_HZ` to Nyquist.
+pub(crate) fn log_bands(levels: &[f32], bin_hz: f32, count: usize) -> Vec<(f32, f32)> {
+    let max_hz = bin_hz * (levels.len() - 1) as f32;
+    let ratio = (max_hz / MIN_HZ).powf(1.0 / count as f32);
+
+    (0..count)
+        .map(|band| {
+            let low = MIN_HZ * ratio.powi(band as i32);
+            let high = low * ratio;
+            let first = (low / bin_hz).round() as usize;
+            // bands narrower than a bin at low frequencies show the nearest one
+            let last = ((high / bin_hz).round() as usize).clamp(first + 1, levels.len());
+            let level = levels[first.min(levels.len() - 1)..last]
+                .iter()
+                .copied()
+                .fold(FLOOR_DB, f32::max);
+            ((low * high).sqrt(), level)
+        })
+        .collect()
+}
+
+/// Last spectra analyzed, scrolling as new ones come in
+pub(crate) struct Spectrogram {
+    spectra: VecDeque<Vec<f32>>,
+}
+
+impl Spectrogram {
+    pub fn new() -> Self {
+        Self {
+            spectra: VecDeque::with_capacity(SPECTROGRAM_LENGTH),
+        }
+    }
+
+    pub fn push(&mut self, levels: Vec<f32>) {
+        if self.spectra.len() == SPECTROGRAM_LENGTH {
+            self.spectra.pop_front();
+        }
+        self.spectra.push_back(levels);
+    }
+
+    /// Up to `count` spectra, from the oldest to the most recent
+    pub fn last(&self, count: usize) -> impl Iterator<Item = &[f32]> {
+        self.spectra
+            .iter()
+            .skip(self.spectra.len().saturating_sub(count))
+            .map(Vec::as_slice)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{log_bands, parse_fft_size, Analyzer, Spectrogram, FLOOR_DB};
+
+    use std::f32::consts::PI;
+
+    #[test]
+    fn find_sine_levels() {
+        let mut analyzer = Analyzer::new(1024, 48000);
+        // right on the 20th bin
+        let hz = 20.0 * analyzer.bin_hz();
+        let sine = (0..1024).map(|i| 0.5 * (2.0 * PI * hz * i as f32 / 48000.0).sin());
+        let levels = analyzer.analyze(sine);
+
+        assert_eq!(levels.len(), 513);
+        assert!((levels[20] + 6.02).abs() < 0.1, "{}", levels[20]);
+        assert!(levels[40] < -60.0);
+        assert_eq!(analyzer.analyze(std::iter::empty())[20], FLOOR_DB);
+    }
+
+    #[test]
+    fn spread_bands_on_log_axis() {
+        let mut levels = vec![FLOOR_DB; 513];
+        levels[200] = -10.0;
+        let bands = log_bands(&levels, 46.875, 30);
+
+        assert_eq!(bands.len(), 30);
+        assert!(bands.windows(2).all(|w| w[1].0 > w[0].0 * 1.2));
+        let loud = bands.iter().filter(|(_, level)| *level == -10.0).count();
+        assert_eq!(loud, 1);
+    }
+
+    #[test]
+    fn scroll_spectrogram() {
+        let mut spectrogram = Spectrogram::new();
+        for i in 0..600 {
+            spectrogram.push(vec![i as f32]);
+        }
+        let last: Vec<_> = spectrogram.last(2).map(|levels| levels[0]).collect();
+        assert_eq!(last, [598.0, 599.0]);
+        assert_eq!(spectrogram.last(1000).count(), 512);
+    }
+
+    #[test]
+    fn accept_powers_of_two() {
+        assert_eq!(parse_fft_size("4096"), Ok(4096));
+        assert!(parse_fft_size("1000").is_err());
+        assert!(parse_fft_size("128").is_err());
+        assert!(parse_fft_size("big").is_err());
+    }
+}
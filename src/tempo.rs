@@ -0,0 +1,97 @@
+use std::time::{Duration, Instant};
+
+pub(crate) const MIN_BPM: f32 = 20.0;
+pub(crate) const MAX_BPM: f32 = 400.0;
+
+/// Taps further apart than this start a new measurement
+const TAP_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Taps averaged to get the tempo
+const MAX_TAPS: usize = 8;
+
+/// Keep a tempo within the range the engine handles well
+pub(crate) fn clamp_bpm(bpm: f32) -> f32 {
+    bpm.clamp(MIN_BPM, MAX_BPM)
+}
+
+/// Check a tempo given on the command line
+pub(crate) fn parse_bpm(arg: &str) -> Result<f32, String> {
+    let bpm: f32 = arg.parse().map_err(|e| format!("{e}"))?;
+    if !(MIN_BPM..=MAX_BPM).contains(&bpm) {
+        return Err(format!("must be between {MIN_BPM} and {MAX_BPM} BPM"));
+    }
+    Ok(bpm)
+}
+
+/// Tempo derived from the time between the last key taps
+#[derive(Default)]
+pub(crate) struct TapTempo {
+    taps: Vec<Instant>,
+}
+
+impl TapTempo {
+    /// Register a tap, returning the tempo once there are at least two taps
+    pub fn tap(&mut self, now: Instant) -> Option<f32> {
+        if self
+            .taps
+            .last()
+            .is_some_and(|last| now.duration_since(*last) > TAP_TIMEOUT)
+        {
+            self.taps.clear();
+        }
+        if self.taps.len() == MAX_TAPS {
+            self.taps.remove(0);
+        }
+        self.taps.push(now);
+
+        let intervals = self.taps.len() - 1;
+        if intervals == 0 {
+            return None;
+        }
+        let beat = self.taps[intervals]
+            .duration_since(self.taps[0])
+            .as_secs_f32()
+            / intervals as f32;
+        Some(clamp_bpm(60.0 / beat))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_bpm, TapTempo, MAX_BPM};
+
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn average_taps() {
+        let start = Instant::now();
+        let mut tempo = TapTempo::default();
+
+        assert_eq!(tempo.tap(start), None);
+        assert_eq!(tempo.tap(start + Duration::from_millis(500)), Some(120.0));
+        let bpm = tempo.tap(start + Duration::from_millis(1100)).unwrap();
+        assert!((bpm - 109.09).abs() < 0.01, "bpm {bpm}");
+    }
+
+    #[test]
+    fn restart_after_a_pause() {
+        let start = Instant::now();
+        let mut tempo = TapTempo::default();
+
+        tempo.tap(start);
+        tempo.tap(start + Duration::from_millis(500));
+        assert_eq!(tempo.tap(start + Duration::from_secs(5)), None);
+        assert_eq!(
+            tempo.tap(start + Duration::from_millis(5001)),
+            Some(MAX_BPM)
+        );
+    }
+
+    #[test]
+    fn accept_tempos() {
+        assert_eq!(parse_bpm("90.5"), Ok(90.5));
+        assert!(parse_bpm("0").is_err());
+        assert!(parse_bpm("-120").is_err());
+        assert!(parse_bpm("fast").is_err());
+    }
+}
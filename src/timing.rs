@@ -0,0 +1,188 @@
+use std::{
+    fmt,
+    sync::{
+        atomic::{AtomicU32, AtomicU64, Ordering},
+        Arc,
+    },
+    thread,
+    time::Duration,
+};
+
+use anyhow::{Context, Result};
+use tracing::info;
+
+use crate::SampleData;
+
+/// Width of the buckets of the callback time histogram
+const BUCKET: Duration = Duration::from_micros(20);
+
+/// Buckets of the histogram, the last one catching the longer callbacks
+const BUCKETS: usize = 1000;
+
+/// A callback coming this much later than the previous buffer's length means audio was dropped
+const GAP_RATIO: f32 = 1.5;
+
+/// Whether the time between two callbacks shows audio was dropped
+pub(crate) fn is_gap(since_last: Duration, last_buffer: Duration) -> bool {
+    since_last.as_secs_f32() > last_buffer.as_secs_f32() * GAP_RATIO
+}
+
+/// How long the audio callbacks take, recorded by the callback itself
+pub(crate) struct CallbackStats {
+    callbacks: AtomicU64,
+    /// Callbacks taking longer than the audio they make lasts
+    overruns: AtomicU64,
+    /// Callbacks called late, from the stream timestamps
+    gaps: AtomicU64,
+    max_ns: AtomicU64,
+    histogram: Box<[AtomicU32]>,
+    /// Highest load since the last read, as `f32` bits
+    peak_load: AtomicU32,
+}
+
+impl CallbackStats {
+    pub fn new() -> Self {
+        Self {
+            callbacks: AtomicU64::new(0),
+            overruns: AtomicU64::new(0),
+            gaps: AtomicU64::new(0),
+            max_ns: AtomicU64::new(0),
+            histogram: (0..BUCKETS).map(|_| AtomicU32::new(0)).collect(),
+            peak_load: AtomicU32::new(0),
+        }
+    }
+
+    /// Record a callback that took `elapsed` to make `budget` of audio
+    pub fn record(&self, elapsed: Duration, budget: Duration) {
+        self.callbacks.fetch_add(1, Ordering::Relaxed);
+        if elapsed > budget {
+            self.overruns.fetch_add(1, Ordering::Relaxed);
+        }
+        let ns = elapsed.as_nanos() as u64;
+        self.max_ns.fetch_max(ns, Ordering::Relaxed);
+        let bucket = (ns / BUCKET.as_nanos() as u64).min(BUCKETS as u64 - 1);
+        self.histogram[bucket as usize].fetch_add(1, Ordering::Relaxed);
+
+        let load = elapsed.as_secs_f32() / budget.as_secs_f32();
+        // positive floats order like their bits
+        self.peak_load.fetch_max(load.to_bits(), Ordering::Relaxed);
+    }
+
+    pub fn record_gap(&self) {
+        self.gaps.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Highest load since the last call, 1 being the whole budget
+    pub fn take_peak_load(&self) -> f32 {
+        f32::from_bits(self.peak_load.swap(0, Ordering::Relaxed))
+    }
+
+    pub fn summary(&self) -> Summary {
+        let counts: Vec<_> = self
+            .histogram
+            .iter()
+            .map(|count| count.load(Ordering::Relaxed) as u64)
+            .collect();
+        Summary {
+            callbacks: self.callbacks.load(Ordering::Relaxed),
+            overruns: self.overruns.load(Ordering::Relaxed),
+            gaps: self.gaps.load(Ordering::Relaxed),
+            max: Duration::from_nanos(self.max_ns.load(Ordering::Relaxed)),
+            p50: percentile(&counts, 0.5),
+            p99: percentile(&counts, 0.99),
+        }
+    }
+}
+
+/// Upper bound of the bucket holding the quantile
+fn percentile(counts: &[u64], quantile: f64) -> Duration {
+    let total: u64 = counts.iter().sum();
+    let rank = (total as f64 * quantile).ceil() as u64;
+    let mut seen = 0;
+    for (bucket, count) in counts.iter().enumerate() {
+        seen += count;
+        if seen >= rank.max(1) {
+            return BUCKET * (bucket as u32 + 1);
+        }
+    }
+    Duration::ZERO
+}
+
+/// Callback statistics since the start
+pub(crate) struct Summary {
+    pub callbacks: u64,
+    pub overruns: u64,
+    pub gaps: u64,
+    pub max: Duration,
+    pub p50: Duration,
+    pub p99: Duration,
+}
+
+impl fmt::Display for Summary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let ms = |duration: Duration| duration.as_secs_f64() * 1000.0;
+        write!(
+            f,
+            "{} callbacks, {} over budget, {} gaps, max {:.2} ms, p50 {:.2} ms, p99 {:.2} ms",
+            self.callbacks,
+            self.overruns,
+            self.gaps,
+            ms(self.max),
+            ms(self.p50),
+            ms(self.p99)
+        )
+    }
+}
+
+/// Log the statistics every `interval` when there is no TUI to show them
+pub(crate) fn spawn_stats_printer(sample_data: Arc<SampleData>, interval: Duration) -> Result<()> {
+    thread::Builder::new()
+        .name(String::from("stats"))
+        .spawn(move || loop {
+            thread::sleep(interval);
+            info!("audio callbacks: {}", sample_data.timing.summary());
+        })
+        .context("spawn stats thread")?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{is_gap, CallbackStats};
+
+    use std::time::Duration;
+
+    #[test]
+    fn summarize_callbacks() {
+        let stats = CallbackStats::new();
+        let budget = Duration::from_micros(2900);
+        for _ in 0..98 {
+            stats.record(Duration::from_micros(500), budget);
+        }
+        stats.record(Duration::from_micros(2000), budget);
+        stats.record(Duration::from_micros(3500), budget);
+        stats.record_gap();
+
+        let summary = stats.summary();
+        assert_eq!(summary.callbacks, 100);
+        assert_eq!(summary.overruns, 1);
+        assert_eq!(summary.gaps, 1);
+        assert_eq!(summary.max, Duration::from_micros(3500));
+        assert_eq!(summary.p50, Duration::from_micros(520));
+        assert_eq!(summary.p99, Duration::from_micros(2020));
+        assert_eq!(
+            summary.to_string(),
+            "100 callbacks, 1 over budget, 1 gaps, max 3.50 ms, p50 0.52 ms, p99 2.02 ms"
+        );
+
+        assert!((stats.take_peak_load() - 3500.0 / 2900.0).abs() < 1e-6);
+        assert_eq!(stats.take_peak_load(), 0.0);
+    }
+
+    #[test]
+    fn find_gaps() {
+        let buffer = Duration::from_millis(10);
+        assert!(!is_gap(Duration::from_millis(11), buffer));
+        assert!(is_gap(Duration::from_millis(25), buffer));
+    }
+}
@@ -0,0 +1,248 @@
+use std::{collections::BTreeSet, ops::Range};
+
+/// Appended to the chains silenced by a mute or a solo
+const SILENCE: &str = " >> mul 0";
+
+/// A chain of the code, from its `name:` line to the next chain
+struct Chain<'a> {
+    name: &'a str,
+    lines: Range<usize>,
+    /// `~names` used by the chain
+    references: Vec<&'a str>,
+}
+
+/// Name of the chain defined on this line, if any
+fn definition(line: &str) -> Option<&str> {
+    let (name, _) = line.trim_start().split_once(':')?;
+    let bare = name.strip_prefix('~').unwrap_or(name);
+    let valid = !bare.is_empty() && bare.chars().all(|c| c.is_ascii_alphanumeric() || c == '_');
+    valid.then_some(name)
+}
+
+/// Code of a line, without its comment
+fn strip_comment(line: &str) -> &str {
+    line.find("//").map_or(line, |start| &line[..start])
+}
+
+fn chains(code: &str) -> Vec<Chain<'_>> {
+    let lines: Vec<_> = code.lines().collect();
+    let mut chains: Vec<Chain> = vec![];
+    for (i, line) in lines.iter().enumerate() {
+        if let Some(name) = definition(strip_comment(line)) {
+            chains.push(Chain {
+                name,
+                lines: i..i + 1,
+                references: vec![],
+            });
+        } else if let Some(chain) = chains.last_mut() {
+            chain.lines.end = i + 1;
+        }
+    }
+
+    for chain in &mut chains {
+        let mut first = true;
+        for line in &lines[chain.lines.clone()] {
+            let code = strip_comment(line);
+            // skip the name of the chain itself
+            let code = match std::mem::take(&mut first) {
+                true => code.split_once(':').map_or(code, |(_, rest)| rest),
+                false => code,
+            };
+            chain.references.extend(
+                code.split(|c: char| !(c.is_ascii_alphanumeric() || c == '_' || c == '~'))
+                    .filter(|word| word.starts_with('~') && word.len() > 1),
+            );
+        }
+    }
+    chains
+}
+
+/// Names of the `~name:` chains of the code, in order
+pub(crate) fn track_names(code: &str) -> Vec<&str> {
+    chains(code)
+        .into_iter()
+        .map(|chain| chain.name)
+        .filter(|name| name.starts_with('~'))
+        .collect()
+}
+
+/// Tracks muted and soloed from the TUI, applied by rewriting the code
+#[derive(Default)]
+pub(crate) struct TrackSwitches {
+    muted: BTreeSet<String>,
+    soloed: BTreeSet<String>,
+}
+
+impl TrackSwitches {
+    pub fn is_muted(&self, name: &str) -> bool {
+        self.muted.contains(name)
+    }
+
+    pub fn is_soloed(&self, name: &str) -> bool {
+        self.soloed.contains(name)
+    }
+
+    pub fn toggle_mute(&mut self, name: &str) {
+        if !self.muted.remove(name) {
+            self.muted.insert(name.to_owned());
+        }
+    }
+
+    pub fn toggle_solo(&mut self, name: &str) {
+        if !self.soloed.remove(name) {
+            self.soloed.insert(name.to_owned());
+        }
+    }
+
+    /// Chains silenced by the switches: the muted tracks, and the ones neither soloed nor used by
+    /// a soloed one
+    fn silenced<'a>(&self, chains: &'a [Chain<'a>]) -> Vec<&'a Chain<'a>> {
+        let tracks = chains.iter().filter(|chain| chain.name.starts_with('~'));
+
+        let any_solo = tracks.clone().any(|chain| self.is_soloed(chain.name));
+        // soloed tracks keep the tracks they use, like a modulator
+        let mut needed: Vec<&str> = match any_solo {
+            true => tracks
+                .clone()
+                .filter(|chain| self.is_soloed(chain.name))
+                .map(|chain| chain.name)
+                .collect(),
+            false => tracks.clone().map(|chain| chain.name).collect(),
+        };
+        let mut i = 0;
+        while i < needed.len() {
+            let name = needed[i];
+            if let Some(chain) = chains.iter().find(|chain| chain.name == name) {
+                for reference in &chain.references {
+                    if !needed.contains(reference) {
+                        needed.push(reference);
+                    }
+                }
+            }
+            i += 1;
+        }
+
+        tracks
+            .filter(|chain| self.is_muted(chain.name) || !needed.contains(&chain.name))
+            .collect()
+    }
+
+    /// Which lines of the code belong to a silenced chain
+    pub fn silenced_lines(&self, code: &str) -> Vec<bool> {
+        let chains = chains(code);
+        let mut lines = vec![false; code.lines().count()];
+        for chain in self.silenced(&chains) {
+            lines[chain.lines.clone()].fill(true);
+        }
+        lines
+    }
+
+    /// Silence the muted tracks, and the ones neither soloed nor used by a soloed one
+    pub fn apply(&self, code: &str) -> String {
+        let chains = chains(code);
+        let silenced: Vec<_> = self
+            .silenced(&chains)
+            .into_iter()
+            .filter_map(|chain| {
+                // after the last line with some code, comments could follow
+                chain.lines.clone().rev().find(|i| {
+                    !strip_comment(code.lines().nth(*i).unwrap_or(""))
+                        .trim()
+                        .is_empty()
+                })
+            })
+            .collect();
+        if silenced.is_empty() {
+            return code.to_owned();
+        }
+
+        let mut rewritten = String::with_capacity(code.len() + silenced.len() * SILENCE.len());
+        for (i, line) in code.split('\n').enumerate() {
+            if i > 0 {
+                rewritten.push('\n');
+            }
+            if silenced.contains(&i) {
+                let code_end = strip_comment(line).trim_end().len();
+                rewritten.push_str(&line[..code_end]);
+                rewritten.push_str(SILENCE);
+                rewritten.push_str(&line[code_end..]);
+            } else {
+                rewritten.push_str(line);
+            }
+        }
+        rewritten
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{track_names, TrackSwitches};
+
+    use glicol::Engine;
+
+    use crate::BLOCK_SIZE;
+
+    const CODE: &str = "~lfo: sin 0.2 >> mul 500 >> add 800
+~t1: speed 4.0 >> seq 60 >> bd 0.2 // kick
+// bass
+~t2: seq 33_33_ _33
+>> sawsynth 0.01 0.1
+>> lpf ~lfo 1.0
+
+out: mix ~t.. >> plate 0.1";
+
+    #[test]
+    fn find_tracks() {
+        assert_eq!(track_names(CODE), ["~lfo", "~t1", "~t2"]);
+        assert_eq!(track_names("o: sin 440\n// ~a: sin 220"), [] as [&str; 0]);
+    }
+
+    #[test]
+    fn mute_tracks() {
+        let mut switches = TrackSwitches::default();
+        assert_eq!(switches.apply(CODE), CODE);
+
+        switches.toggle_mute("~t1");
+        switches.toggle_mute("~t2");
+        switches.toggle_mute("~missing");
+        assert_eq!(
+            switches.apply(CODE),
+            "~lfo: sin 0.2 >> mul 500 >> add 800
+~t1: speed 4.0 >> seq 60 >> bd 0.2 >> mul 0 // kick
+// bass
+~t2: seq 33_33_ _33
+>> sawsynth 0.01 0.1
+>> lpf ~lfo 1.0 >> mul 0
+
+out: mix ~t.. >> plate 0.1"
+        );
+        let mut engine = Engine::<BLOCK_SIZE>::new();
+        engine.update_with_code(&switches.apply(CODE));
+        assert!(engine.update().is_ok());
+
+        switches.toggle_mute("~t2");
+        assert!(!switches.apply(CODE).contains("1.0 >> mul 0"));
+    }
+
+    #[test]
+    fn solo_tracks_with_what_they_use() {
+        let mut switches = TrackSwitches::default();
+        switches.toggle_solo("~t2");
+        assert_eq!(
+            switches.apply(CODE),
+            "~lfo: sin 0.2 >> mul 500 >> add 800
+~t1: speed 4.0 >> seq 60 >> bd 0.2 >> mul 0 // kick
+// bass
+~t2: seq 33_33_ _33
+>> sawsynth 0.01 0.1
+>> lpf ~lfo 1.0
+
+out: mix ~t.. >> plate 0.1"
+        );
+        assert!(switches.is_soloed("~t2"));
+        assert_eq!(
+            switches.silenced_lines(CODE),
+            [false, true, true, false, false, false, false, false]
+        );
+    }
+}
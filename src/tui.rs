@@ -1,6 +1,8 @@
 use std::{
-    io,
-    sync::{atomic::Ordering, Arc},
+    collections::VecDeque,
+    fs, io,
+    path::PathBuf,
+    sync::{atomic::Ordering, mpsc, Arc, Mutex},
     time::{Duration, Instant},
 };
 
@@ -20,29 +22,165 @@ pub use ratatui::{
 };
 use ratatui::{
     symbols::border,
-    widgets::{Clear, List, ListItem, ListState},
+    widgets::{Clear, List, ListItem, ListState, Paragraph, Sparkline},
 };
 
-use crate::{recent_lines::ShareableRecentLinesBuffer, RB_SIZE};
+use tracing::{info, warn};
+
+use crate::{
+    control::{Control, ControlSender},
+    editor::{Editor, EditorAction},
+    highlight::{highlight_line, Token},
+    master::{MasterState, MAX_VOLUME_DB, MIN_VOLUME_DB},
+    meter::{to_db, Meters},
+    recent_lines::ShareableRecentLinesBuffer,
+    record::RecordState,
+    scope::Scope,
+    spectrum::{log_bands, Analyzer, Spectrogram, FLOOR_DB, MIN_HZ},
+    tempo::{clamp_bpm, TapTempo},
+    timing::Summary,
+    tracks::track_names,
+    updater::{CodeError, CodeStatus},
+    SampleData,
+};
 
 pub enum ExitStatus {
     KeepAudio,
     ExitAll,
 }
 
+/// What the TUI shows and controls
+pub(crate) struct App {
+    pub sample_data: Arc<SampleData>,
+    pub control: ControlSender,
+    pub scope: Scope,
+    pub analyzer: Analyzer,
+    pub record_state: Option<Arc<RecordState>>,
+    pub code_status: Arc<Mutex<CodeStatus>>,
+    pub info: String,
+    pub editor: Editor,
+    /// File being played, saved by the editor
+    pub path: PathBuf,
+    /// File content as changed by other editors
+    pub file_changes: mpsc::Receiver<String>,
+    /// Where the editor sends the code to play, like the file watcher
+    pub code_updates: mpsc::Sender<String>,
+}
+
+/// Where the keys go
+#[derive(PartialEq)]
+enum Focus {
+    Main,
+    /// BPM being typed
+    Bpm(String),
+    Editor,
+    /// Tracks pane, with the selected track
+    Tracks(usize),
+}
+
+/// Callback loads kept for the sparkline, more than any terminal is wide
+const LOAD_HISTORY: usize = 512;
+
+/// Fewest frames the scope shows
+const MIN_SCOPE_WINDOW: usize = 16;
+
+/// Highest vertical zoom of the scope
+const MAX_SCOPE_ZOOM: f32 = 16.0;
+
+/// What the main pane shows, switched with `v`
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum View {
+    Scope,
+    /// Stereo goniometer, the mid channel up and the side one across
+    Xy,
+    Spectrum,
+    Spectrogram,
+}
+
+impl View {
+    fn next(self) -> Self {
+        match self {
+            Self::Scope => Self::Xy,
+            Self::Xy => Self::Spectrum,
+            Self::Spectrum => Self::Spectrogram,
+            Self::Spectrogram => Self::Scope,
+        }
+    }
+}
+
+/// Current view and what they need to be drawn
+struct Views {
+    current: View,
+    /// Frames shown by the scope and XY views
+    window: usize,
+    /// Vertical zoom of the scope and XY views
+    zoom: f32,
+    /// Start the scope on a rising edge
+    trigger: bool,
+    /// Last spectrum analyzed
+    levels: Vec<f32>,
+    spectrogram: Spectrogram,
+    /// Highest callback load between two updates, in percent of the budget
+    load: VecDeque<u64>,
+}
+
+impl Views {
+    /// Follow the callback load, and analyze the frames captured by the scope when a spectrum
+    /// view is shown
+    fn update(&mut self, app: &mut App) {
+        if self.load.len() == LOAD_HISTORY {
+            self.load.pop_front();
+        }
+        let load = app.sample_data.timing.take_peak_load();
+        self.load.push_back((load * 100.0).round() as u64);
+
+        if !matches!(self.current, View::Spectrum | View::Spectrogram) {
+            return;
+        }
+
+        let size = app.analyzer.size();
+        let mono = app
+            .scope
+            .recent(size)
+            .map(|[left, right]| (left + right) / 2.0);
+        self.levels = app.analyzer.analyze(mono);
+        // stop scrolling while nothing plays
+        if self.current == View::Spectrogram && !app.sample_data.paused.load(Ordering::Relaxed) {
+            self.spectrogram.push(self.levels.clone());
+        }
+    }
+}
+
 pub(crate) fn run_app<B: Backend>(
     console_buffer: ShareableRecentLinesBuffer,
     terminal: &mut Terminal<B>,
     tick_rate: Duration,
-    sample_data: Arc<crate::SampleData>,
-    // use_scope: bool,
-    info: String,
-    // right: Arc<AtomicPtr<f32>>
+    mut app: App,
 ) -> io::Result<ExitStatus> {
     let mut last_tick = Instant::now();
+    let mut tap_tempo = TapTempo::default();
+    let mut focus = Focus::Main;
+    let mut meters = Meters::new(Instant::now());
+    let mut views = Views {
+        current: View::Scope,
+        window: app.scope.len(),
+        zoom: 1.0,
+        trigger: true,
+        levels: vec![],
+        spectrogram: Spectrogram::new(),
+        load: VecDeque::with_capacity(LOAD_HISTORY),
+    };
 
     loop {
-        terminal.draw(|f| ui(f, &sample_data, &info, &console_buffer))?;
+        app.scope.update();
+        views.update(&mut app);
+        if meters.update(&app.sample_data.levels, Instant::now()) {
+            warn!("output clipped, press c to clear");
+        }
+        while let Ok(content) = app.file_changes.try_recv() {
+            app.editor.reload(&content);
+        }
+        terminal.draw(|f| ui(f, &mut app, &views, &meters, &focus, &console_buffer))?;
 
         let timeout = tick_rate
             .checked_sub(last_tick.elapsed())
@@ -50,14 +188,95 @@ pub(crate) fn run_app<B: Backend>(
 
         if event::poll(timeout)? {
             if let Event::Key(key) = event::read()? {
+                match &mut focus {
+                    Focus::Main => (),
+                    Focus::Bpm(input) => {
+                        match key.code {
+                            KeyCode::Char(c @ ('0'..='9' | '.')) if input.len() < 6 => {
+                                input.push(c)
+                            }
+                            KeyCode::Backspace => {
+                                input.pop();
+                            }
+                            KeyCode::Enter => {
+                                match input.parse::<f32>() {
+                                    Ok(bpm) if bpm.is_finite() => set_bpm(&app, bpm),
+                                    _ => warn!("invalid BPM '{input}'"),
+                                }
+                                focus = Focus::Main;
+                            }
+                            KeyCode::Esc => focus = Focus::Main,
+                            _ => (),
+                        }
+                        continue;
+                    }
+                    Focus::Editor => {
+                        match app.editor.handle_key(key) {
+                            EditorAction::None => (),
+                            EditorAction::Evaluate => evaluate(&mut app),
+                            EditorAction::Leave => focus = Focus::Main,
+                        }
+                        continue;
+                    }
+                    Focus::Tracks(selected) => {
+                        let names = track_names_of(&app);
+                        match key.code {
+                            KeyCode::Up => *selected = selected.saturating_sub(1),
+                            KeyCode::Down if *selected + 1 < names.len() => *selected += 1,
+                            KeyCode::Char(c @ ('m' | 's')) => {
+                                if let Some(name) = names.get(*selected) {
+                                    switch_track(&app, name, c == 's');
+                                }
+                            }
+                            KeyCode::Esc | KeyCode::Tab => focus = Focus::Main,
+                            _ => (),
+                        }
+                        continue;
+                    }
+                }
+
                 match key.code {
                     KeyCode::Esc => return Ok(ExitStatus::KeepAudio),
                     KeyCode::Char('p' | ' ') => {
-                        // this is only modified from this thread (just read from the other), so we
-                        // don't have to worry about ordering or using like a swap/exchange loop
-                        // when doing this here
-                        let old = sample_data.paused.load(Ordering::Relaxed);
-                        sample_data.paused.store(!old, Ordering::Relaxed);
+                        // OSC and the MIDI clock pause too, a single atomic toggle doesn't lose
+                        // theirs; the callback only reads it, so no ordering is needed
+                        app.sample_data.paused.fetch_xor(true, Ordering::Relaxed);
+                    }
+                    KeyCode::Char('r') => {
+                        if let Some(record_state) = &app.record_state {
+                            record_state.toggle();
+                        }
+                    }
+                    KeyCode::Char('+' | '=') => set_bpm(&app, current_bpm(&app) + 1.0),
+                    KeyCode::Char('-' | '_') => set_bpm(&app, current_bpm(&app) - 1.0),
+                    KeyCode::Char('t') => {
+                        if let Some(bpm) = tap_tempo.tap(Instant::now()) {
+                            set_bpm(&app, bpm);
+                        }
+                    }
+                    KeyCode::Char('b') => focus = Focus::Bpm(String::new()),
+                    KeyCode::Char('e') => focus = Focus::Editor,
+                    KeyCode::Tab => focus = Focus::Tracks(0),
+                    KeyCode::Char('v') => views.current = views.current.next(),
+                    KeyCode::Char('[') => {
+                        views.window = (views.window / 2).max(MIN_SCOPE_WINDOW);
+                    }
+                    KeyCode::Char(']') => {
+                        views.window = (views.window * 2).min(app.scope.capacity());
+                    }
+                    KeyCode::Char(',') => views.zoom = (views.zoom / 2.0).max(1.0),
+                    KeyCode::Char('.') => views.zoom = (views.zoom * 2.0).min(MAX_SCOPE_ZOOM),
+                    KeyCode::Char('g') => views.trigger = !views.trigger,
+                    KeyCode::Char('c') => meters.clipped = false,
+                    KeyCode::Up => set_volume(&app, app.sample_data.master.volume_db() + 1.0),
+                    KeyCode::Down => set_volume(&app, app.sample_data.master.volume_db() - 1.0),
+                    KeyCode::Char('m') => {
+                        let muted = !app.sample_data.master.is_muted();
+                        match muted {
+                            true => warn!("output muted, press m again to unmute"),
+                            false => info!("output unmuted"),
+                        }
+                        app.control.send(Control::Mute(muted));
                     }
                     KeyCode::Char('q') => return Ok(ExitStatus::ExitAll),
                     _ => (),
@@ -72,39 +291,87 @@ pub(crate) fn run_app<B: Backend>(
     }
 }
 
+fn current_bpm(app: &App) -> f32 {
+    f32::from_bits(app.sample_data.bpm.load(Ordering::Relaxed))
+}
+
+fn set_bpm(app: &App, bpm: f32) {
+    let bpm = (clamp_bpm(bpm) * 10.0).round() / 10.0;
+    info!("tempo set to {bpm} BPM");
+    app.control.send(Control::SetBpm(bpm));
+}
+
+fn set_volume(app: &App, volume_db: f32) {
+    let volume_db = volume_db.clamp(MIN_VOLUME_DB, MAX_VOLUME_DB).round();
+    info!("volume set to {volume_db} dB");
+    app.control.send(Control::SetVolume(volume_db));
+}
+
+/// Names of the tracks of the playing code
+fn track_names_of(app: &App) -> Vec<String> {
+    let status = app.code_status.lock().expect("poisoned lock");
+    status.source.as_deref().map_or(vec![], |source| {
+        track_names(source).into_iter().map(String::from).collect()
+    })
+}
+
+/// Toggle the mute or solo of a track, playing the code again with it
+fn switch_track(app: &App, name: &str, solo: bool) {
+    let source = {
+        let mut status = app.code_status.lock().expect("poisoned lock");
+        match solo {
+            true => status.tracks.toggle_solo(name),
+            false => status.tracks.toggle_mute(name),
+        }
+        status.source.clone()
+    };
+    if let Some(source) = source {
+        if app.code_updates.send(source).is_err() {
+            warn!("updater is gone, can't switch {name}");
+        }
+    }
+}
+
+/// Save the editor buffer and play it
+fn evaluate(app: &mut App) {
+    let code = app.editor.text();
+    if let Err(e) = fs::write(&app.path, &code) {
+        warn!("save {}: {e}", app.path.display());
+        return;
+    }
+    app.editor.mark_saved();
+    info!("evaluating the edited code");
+    // the watcher sends it too, the updater skips the code already playing
+    if app.code_updates.send(code).is_err() {
+        warn!("updater is gone, can't play the edited code");
+    }
+}
+
 fn ui(
     f: &mut Frame,
-    sample_data: &Arc<crate::SampleData>,
-    info: &str,
+    app: &mut App,
+    views: &Views,
+    meters: &Meters,
+    focus: &Focus,
     console_buffer: &ShareableRecentLinesBuffer,
 ) {
-    let mut data = [0.0; RB_SIZE];
-    let mut data2 = [0.0; RB_SIZE];
-    let ptr = sample_data.left_ptr.load(Ordering::Acquire);
-    let ptr2 = sample_data.right_ptr.load(Ordering::Acquire);
-
-    let mut idx = sample_data.index.load(Ordering::Acquire);
-
-    for i in 0..RB_SIZE {
-        data[RB_SIZE - 1 - i] = unsafe { ptr.add(idx).read() };
-        data2[RB_SIZE - 1 - i] = unsafe { ptr2.add(idx).read() };
-        if idx == 0 {
-            idx = RB_SIZE - 1; // read from the tail
-        } else {
-            idx -= 1;
-        }
-    }
+    let sample_data = &app.sample_data;
 
-    let left: Vec<(f64, f64)> = data
-        .into_iter()
-        .enumerate()
-        .map(|(x, y)| (x as f64, y as f64))
-        .collect();
-    let right: Vec<(f64, f64)> = data2
-        .into_iter()
-        .enumerate()
-        .map(|(x, y)| (x as f64, y as f64))
-        .collect();
+    let frames: Vec<_> = match views.trigger && views.current == View::Scope {
+        true => app.scope.triggered(views.window).collect(),
+        false => app.scope.recent(views.window).collect(),
+    };
+    let channel = |chan: usize| -> Vec<(f64, f64)> {
+        frames
+            .iter()
+            .enumerate()
+            .map(|(x, frame)| (x as f64, frame[chan] as f64))
+            .collect()
+    };
+    let left = channel(0);
+    let right = channel(1);
+    let scope_length = frames.len() as f64;
+    let range = 1.0 / views.zoom as f64;
 
     let size = f.size();
     let chunks = Layout::default()
@@ -130,12 +397,27 @@ fn ui(
     //         .add_modifier(Modifier::ITALIC | Modifier::BOLD),
     // );
 
-    let label = Span::styled(
-        "press esc to exit tui, or q to exit program",
-        Style::default()
-            .fg(Color::Yellow)
-            .add_modifier(Modifier::ITALIC | Modifier::BOLD),
-    );
+    let device = &app.sample_data.device;
+    let label = match device.reconnect_attempts() {
+        Some(attempts) => Span::styled(
+            format!("audio device lost, reconnecting (attempt {attempts})"),
+            Style::default()
+                .fg(Color::Red)
+                .add_modifier(Modifier::ITALIC | Modifier::BOLD),
+        ),
+        None if device.input_lost() => Span::styled(
+            "audio input lost, ~input is silent until it's back",
+            Style::default()
+                .fg(Color::Red)
+                .add_modifier(Modifier::ITALIC | Modifier::BOLD),
+        ),
+        None => Span::styled(
+            "press esc to exit tui, or q to exit program",
+            Style::default()
+                .fg(Color::Yellow)
+                .add_modifier(Modifier::ITALIC | Modifier::BOLD),
+        ),
+    };
 
     let gauge = Gauge::default()
         .block(
@@ -147,10 +429,37 @@ fn ui(
         .ratio(portion as f64)
         .label(label)
         .use_unicode(true);
-    f.render_widget(gauge, chunks[0]);
+
+    let gauge_row = Layout::default()
+        .direction(Direction::Horizontal)
+        .constraints(
+            [
+                Constraint::Min(0),
+                Constraint::Length(18),
+                Constraint::Length(22),
+                Constraint::Length(if app.record_state.is_some() { 16 } else { 0 }),
+            ]
+            .as_ref(),
+        )
+        .split(chunks[0]);
+    let load_row = Layout::default()
+        .direction(Direction::Horizontal)
+        .constraints([Constraint::Min(0), Constraint::Percentage(50)].as_ref())
+        .split(gauge_row[0]);
+    f.render_widget(gauge, load_row[0]);
+    render_load(f, load_row[1], &views.load, &sample_data.timing.summary());
+    let bpm_input = match focus {
+        Focus::Bpm(input) => Some(input.as_str()),
+        _ => None,
+    };
+    render_tempo(f, gauge_row[1], current_bpm(app), bpm_input);
+    render_master(f, gauge_row[2], &app.sample_data.master);
+    if let Some(record_state) = app.record_state.as_deref() {
+        render_record_indicator(f, gauge_row[3], record_state);
+    }
 
     let x_labels = vec![Span::styled(
-        "[0, 200]",
+        format!("[0, {scope_length}]"),
         Style::default().add_modifier(Modifier::BOLD),
     )];
     let datasets = vec![
@@ -172,7 +481,7 @@ fn ui(
         .block(
             Block::default()
                 .title(Span::styled(
-                    info.replace("SupportedStreamConfig", ""),
+                    format!("{:?} {}", device.name(), app.info),
                     Style::default()
                         .fg(Color::Cyan)
                         .add_modifier(Modifier::BOLD),
@@ -181,23 +490,81 @@ fn ui(
         )
         .x_axis(
             Axis::default()
-                .title("X Axis")
+                .title(match views.trigger {
+                    true => "frames ([ ]) trigger on (g)",
+                    false => "frames ([ ]) trigger off (g)",
+                })
                 .style(Style::default().fg(Color::Gray))
                 .labels(x_labels)
-                .bounds([0., 200.]),
+                .bounds([0., scope_length]),
         )
         .y_axis(
             Axis::default()
-                .title("Y Axis")
+                .title("zoom (, .)")
                 .style(Style::default().fg(Color::Gray))
                 .labels(vec![
-                    Span::styled("-1", Style::default().add_modifier(Modifier::BOLD)),
+                    Span::styled(
+                        format!("{}", -range),
+                        Style::default().add_modifier(Modifier::BOLD),
+                    ),
                     Span::raw("0"),
-                    Span::styled("1", Style::default().add_modifier(Modifier::BOLD)),
+                    Span::styled(
+                        format!("{range}"),
+                        Style::default().add_modifier(Modifier::BOLD),
+                    ),
                 ])
-                .bounds([-1., 1.]),
+                .bounds([-range, range]),
         );
-    f.render_widget(chart, chunks[1]);
+
+    let main_row = Layout::default()
+        .direction(Direction::Horizontal)
+        .constraints([Constraint::Percentage(50), Constraint::Percentage(50)].as_ref())
+        .split(chunks[1]);
+    let code_column = Layout::default()
+        .direction(Direction::Vertical)
+        .constraints([Constraint::Percentage(50), Constraint::Percentage(50)].as_ref())
+        .split(main_row[0]);
+    render_editor(f, code_column[0], &mut app.editor, *focus == Focus::Editor);
+    let playing_row = Layout::default()
+        .direction(Direction::Horizontal)
+        .constraints([Constraint::Min(0), Constraint::Length(20)].as_ref())
+        .split(code_column[1]);
+
+    let code_error = {
+        let code_status = app.code_status.lock().expect("poisoned lock");
+        render_playing_code(f, playing_row[0], &code_status);
+        let selected = match focus {
+            Focus::Tracks(selected) => Some(*selected),
+            _ => None,
+        };
+        render_tracks(f, playing_row[1], &code_status, selected);
+        code_status.error.clone()
+    };
+    let view_column = Layout::default()
+        .direction(Direction::Vertical)
+        .constraints([Constraint::Length(4), Constraint::Min(0)].as_ref())
+        .split(main_row[1]);
+    render_meters(f, view_column[0], meters);
+
+    let view_area = match code_error {
+        Some(code_error) => {
+            let scope_area = Layout::default()
+                .direction(Direction::Vertical)
+                .constraints([Constraint::Min(0), Constraint::Length(5)].as_ref())
+                .split(view_column[1]);
+            render_code_error(f, scope_area[1], &code_error);
+            scope_area[0]
+        }
+        None => view_column[1],
+    };
+    match views.current {
+        View::Scope => f.render_widget(chart, view_area),
+        View::Xy => render_xy(f, view_area, &frames, range),
+        View::Spectrum => render_spectrum(f, view_area, &views.levels, app.analyzer.bin_hz()),
+        View::Spectrogram => {
+            render_spectrogram(f, view_area, &views.spectrogram, app.analyzer.bin_hz())
+        }
+    }
 
     if sample_data.paused.load(Ordering::Relaxed) {
         let frame_area = f.size();
@@ -232,6 +599,486 @@ fn ui(
     render_console(f, chunks[2], console_buffer);
 }
 
+fn render_xy(f: &mut Frame<'_>, area: Rect, frames: &[[f32; 2]], range: f64) {
+    // a mono signal is a vertical line, out of phase channels a horizontal one
+    let points = frames
+        .iter()
+        .map(|[left, right]| {
+            let (left, right) = (*left as f64, *right as f64);
+            (
+                (right - left) * std::f64::consts::FRAC_1_SQRT_2,
+                (left + right) * std::f64::consts::FRAC_1_SQRT_2,
+            )
+        })
+        .collect::<Vec<_>>();
+
+    let chart = Chart::new(vec![Dataset::default()
+        .marker(symbols::Marker::Braille)
+        .graph_type(GraphType::Scatter)
+        .style(Style::default().fg(Color::Cyan))
+        .data(&points)])
+    .block(
+        Block::bordered()
+            .title(" xy (v) zoom (, .) ")
+            .border_set(border::ROUNDED),
+    )
+    .x_axis(
+        Axis::default()
+            .title("S")
+            .style(Style::default().fg(Color::Gray))
+            .labels(vec![Span::raw("L"), Span::raw("R")])
+            .bounds([-range, range]),
+    )
+    .y_axis(
+        Axis::default()
+            .title("M")
+            .style(Style::default().fg(Color::Gray))
+            .bounds([-range, range]),
+    );
+    f.render_widget(chart, area);
+}
+
+fn render_spectrum(f: &mut Frame<'_>, area: Rect, levels: &[f32], bin_hz: f32) {
+    if levels.is_empty() {
+        return;
+    }
+
+    // braille dots are two per cell
+    let bands = log_bands(levels, bin_hz, area.width as usize * 2);
+    let points = bands
+        .iter()
+        .map(|(hz, level)| ((*hz as f64).log10(), *level as f64))
+        .collect::<Vec<_>>();
+    let (low, high) = (
+        (MIN_HZ as f64).log10(),
+        (bin_hz as f64 * (levels.len() - 1) as f64).log10(),
+    );
+    let hz_label = |log_hz: f64| {
+        let hz = 10f64.powf(log_hz);
+        match hz >= 1000.0 {
+            true => format!("{:.1}k", hz / 1000.0),
+            false => format!("{hz:.0}"),
+        }
+    };
+
+    let chart = Chart::new(vec![Dataset::default()
+        .marker(symbols::Marker::Braille)
+        .graph_type(GraphType::Line)
+        .style(Style::default().fg(Color::Green))
+        .data(&points)])
+    .block(
+        Block::bordered()
+            .title(" spectrum (v) ")
+            .border_set(border::ROUNDED),
+    )
+    .x_axis(
+        Axis::default()
+            .title("Hz")
+            .style(Style::default().fg(Color::Gray))
+            .labels(vec![
+                Span::raw(hz_label(low)),
+                Span::raw(hz_label((low + high) / 2.0)),
+                Span::raw(hz_label(high)),
+            ])
+            .bounds([low, high]),
+    )
+    .y_axis(
+        Axis::default()
+            .title("dB")
+            .style(Style::default().fg(Color::Gray))
+            .labels(vec![
+                Span::raw(format!("{FLOOR_DB}")),
+                Span::raw(format!("{}", FLOOR_DB / 2.0)),
+                Span::raw("0"),
+            ])
+            .bounds([FLOOR_DB as f64, 0.0]),
+    );
+    f.render_widget(chart, area);
+}
+
+fn render_spectrogram(f: &mut Frame<'_>, area: Rect, spectrogram: &Spectrogram, bin_hz: f32) {
+    let block = Block::bordered()
+        .title(" spectrogram (v) ")
+        .border_set(border::ROUNDED);
+    let inner = block.inner(area);
+    f.render_widget(block, area);
+
+    // each cell shows two bands, the upper one with a half block and the lower one behind it
+    let rows = inner.height as usize;
+    let columns = spectrogram
+        .last(inner.width as usize)
+        .map(|levels| log_bands(levels, bin_hz, rows * 2))
+        .collect::<Vec<_>>();
+    let padding = " ".repeat(inner.width as usize - columns.len());
+
+    let lines = (0..rows)
+        .map(|row| {
+            let upper = rows * 2 - 1 - row * 2;
+            let mut spans = vec![Span::raw(padding.as_str())];
+            spans.extend(columns.iter().map(|bands| {
+                Span::styled(
+                    "▀",
+                    Style::new()
+                        .fg(heat_color(bands[upper].1))
+                        .bg(heat_color(bands[upper - 1].1)),
+                )
+            }));
+            Line::from(spans)
+        })
+        .collect::<Vec<_>>();
+    f.render_widget(Paragraph::new(lines), inner);
+}
+
+/// Colour of a level, from black for the floor to pale yellow at full scale
+fn heat_color(level: f32) -> Color {
+    const STOPS: [(f32, f32, f32); 5] = [
+        (0.0, 0.0, 0.0),
+        (30.0, 30.0, 160.0),
+        (170.0, 30.0, 150.0),
+        (250.0, 130.0, 20.0),
+        (255.0, 250.0, 200.0),
+    ];
+    let position = (1.0 - level / FLOOR_DB).clamp(0.0, 1.0) * (STOPS.len() - 1) as f32;
+    let index = (position as usize).min(STOPS.len() - 2);
+    let t = position - index as f32;
+    let (from, to) = (STOPS[index], STOPS[index + 1]);
+    let mix = |a: f32, b: f32| (a + (b - a) * t) as u8;
+    Color::Rgb(mix(from.0, to.0), mix(from.1, to.1), mix(from.2, to.2))
+}
+
+fn render_meters(f: &mut Frame<'_>, area: Rect, meters: &Meters) {
+    // lowest level shown, in dBFS
+    const MIN_DB: f32 = -60.0;
+
+    let clip_light = match meters.clipped {
+        true => Span::styled(
+            " ● CLIP ",
+            Style::new().fg(Color::Red).add_modifier(Modifier::BOLD),
+        ),
+        false => Span::styled(" ○ clip ", Style::new().fg(Color::Gray)),
+    };
+    let block = Block::bordered()
+        .title(Line::from(vec![Span::raw(" levels (c) "), clip_light]))
+        .border_set(border::ROUNDED);
+    let inner = block.inner(area);
+    f.render_widget(block, area);
+
+    let width = (inner.width as usize).saturating_sub(10);
+    let position = |level: f32| {
+        let ratio = ((to_db(level) - MIN_DB) / -MIN_DB).clamp(0.0, 1.0);
+        (ratio * width as f32).round() as usize
+    };
+    let zone_color = |cell: usize| {
+        let db = MIN_DB - MIN_DB * cell as f32 / width as f32;
+        match db {
+            db if db < -12.0 => Color::Green,
+            db if db < -3.0 => Color::Yellow,
+            _ => Color::Red,
+        }
+    };
+
+    let lines = ["L ", "R "]
+        .into_iter()
+        .enumerate()
+        .map(|(channel, label)| {
+            let (rms, peak) = (
+                position(meters.rms[channel]),
+                position(meters.peak[channel]),
+            );
+            let held = meters.held_peak(channel);
+            let hold = position(held).min(width.saturating_sub(1));
+
+            let mut spans = vec![Span::raw(label)];
+            spans.extend((0..width).map(|cell| match cell {
+                cell if cell == hold && held > 0.0 => {
+                    Span::styled("│", Style::new().fg(zone_color(cell)))
+                }
+                cell if cell < rms => Span::styled("█", Style::new().fg(zone_color(cell))),
+                cell if cell < peak => Span::styled("▒", Style::new().fg(zone_color(cell))),
+                _ => Span::styled("·", Style::new().fg(Color::DarkGray)),
+            }));
+            spans.push(Span::raw(format!(" {:>+6.1}", to_db(held))));
+            Line::from(spans)
+        })
+        .collect::<Vec<_>>();
+    f.render_widget(Paragraph::new(lines), inner);
+}
+
+fn render_tempo(f: &mut Frame<'_>, area: Rect, bpm: f32, bpm_input: Option<&str>) {
+    let label = match bpm_input {
+        Some(input) => Span::styled(
+            format!("> {input}_"),
+            Style::new().fg(Color::Yellow).add_modifier(Modifier::BOLD),
+        ),
+        None => Span::styled(
+            format!("{bpm:.1}"),
+            Style::new().fg(Color::Cyan).add_modifier(Modifier::BOLD),
+        ),
+    };
+
+    let block = Block::bordered().title(" bpm (+ - t b) ");
+    let inner = block.inner(area);
+    f.render_widget(block, area);
+    f.render_widget(label, inner);
+}
+
+fn render_master(f: &mut Frame<'_>, area: Rect, master: &MasterState) {
+    let volume = match master.is_muted() {
+        true => Span::styled(
+            "MUTED",
+            Style::new().fg(Color::Red).add_modifier(Modifier::BOLD),
+        ),
+        false => Span::styled(
+            format!("{:+.0} dB", master.volume_db()),
+            Style::new().fg(Color::Cyan).add_modifier(Modifier::BOLD),
+        ),
+    };
+    // gain reduction of the limiter
+    let reduction = master.gain_reduction_db();
+    let limiting = match reduction >= 0.1 {
+        true => Span::styled(
+            format!(" GR -{reduction:.1}"),
+            Style::new().fg(Color::Yellow),
+        ),
+        false => Span::styled(" GR 0", Style::new().fg(Color::Gray)),
+    };
+
+    let block = Block::bordered().title(" vol (↑ ↓ m) ");
+    let inner = block.inner(area);
+    f.render_widget(block, area);
+    f.render_widget(Line::from(vec![volume, limiting]), inner);
+}
+
+fn render_load(f: &mut Frame<'_>, area: Rect, load: &VecDeque<u64>, summary: &Summary) {
+    let ms = |duration: Duration| duration.as_secs_f64() * 1000.0;
+    let title = format!(
+        " load · {} over · {} gaps · max {:.1} · p99 {:.1} ms ",
+        summary.overruns,
+        summary.gaps,
+        ms(summary.max),
+        ms(summary.p99)
+    );
+    let width = area.width.saturating_sub(2) as usize;
+    let recent: Vec<_> = load
+        .iter()
+        .skip(load.len().saturating_sub(width))
+        .copied()
+        .collect();
+
+    let sparkline = Sparkline::default()
+        .block(Block::bordered().title(title))
+        .data(&recent)
+        // over budget saturates
+        .max(100)
+        .style(match summary.overruns {
+            0 => Style::new().fg(Color::Green),
+            _ => Style::new().fg(Color::Yellow),
+        });
+    f.render_widget(sparkline, area);
+}
+
+fn render_record_indicator(f: &mut Frame<'_>, area: Rect, record_state: &RecordState) {
+    let label = if record_state.recording.load(Ordering::Relaxed) {
+        let elapsed = record_state.elapsed().as_secs();
+        Span::styled(
+            format!("● {:02}:{:02}", elapsed / 60, elapsed % 60),
+            Style::new().fg(Color::Red).add_modifier(Modifier::BOLD),
+        )
+    } else {
+        Span::styled("○ off", Style::new().fg(Color::Gray))
+    };
+
+    let block = Block::bordered().title(" rec (r) ");
+    let inner = block.inner(area);
+    f.render_widget(block, area);
+    f.render_widget(label, inner);
+}
+
+fn render_editor(f: &mut Frame<'_>, area: Rect, editor: &mut Editor, editing: bool) {
+    let title = match (editing, editor.is_modified()) {
+        (true, true) => " code [+] (ctrl-enter or ctrl-s to play, esc to leave) ",
+        (true, false) => " code (ctrl-enter or ctrl-s to play, esc to leave) ",
+        (false, true) => " code [+] (e to edit) ",
+        (false, false) => " code (e to edit) ",
+    };
+    let block = Block::bordered()
+        .title(title)
+        .border_set(border::ROUNDED)
+        .border_style(match editing {
+            true => Style::new().fg(Color::Yellow),
+            false => Style::new(),
+        });
+    let inner = block.inner(area);
+    f.render_widget(block, area);
+    if inner.width == 0 || inner.height == 0 {
+        return;
+    }
+
+    let (row, col) = editor.scroll_to_cursor(inner.width as usize, inner.height as usize);
+    let (top, left) = editor.scroll();
+    let lines = editor
+        .lines()
+        .iter()
+        .skip(top)
+        .take(inner.height as usize)
+        .map(|line| Line::raw(line.chars().skip(left).collect::<String>()))
+        .collect::<Vec<_>>();
+    f.render_widget(Paragraph::new(lines), inner);
+
+    if editing {
+        f.set_cursor(inner.x + col as u16, inner.y + row as u16);
+    }
+}
+
+fn render_playing_code(f: &mut Frame<'_>, area: Rect, code_status: &CodeStatus) {
+    let block = Block::bordered()
+        .title(" playing ")
+        .border_set(border::ROUNDED);
+    let inner = block.inner(area);
+    f.render_widget(block, area);
+    // what was written, the silenced tracks are dimmed rather than shown with their `>> mul 0`
+    let Some(code) = &code_status.source else {
+        return;
+    };
+    let silenced = code_status.tracks.silenced_lines(code);
+
+    // the row of the rejected line, when it's also in the playing code
+    let error_row = code_status.error.as_ref().and_then(|e| {
+        let (line, _) = e.position?;
+        let row = line.checked_sub(1)?;
+        (code.lines().nth(row) == e.line.as_deref()).then_some(row)
+    });
+    let gutter_width = code.lines().count().to_string().len();
+    // show the first change when it doesn't fit
+    let top = code_status
+        .changed
+        .iter()
+        .position(|changed| *changed)
+        .map_or(0, |first| first.saturating_sub(inner.height as usize / 2));
+
+    let lines = code
+        .lines()
+        .enumerate()
+        .skip(top)
+        .map(|(i, line)| {
+            let (marker, marker_style) = if error_row == Some(i) {
+                (
+                    "!",
+                    Style::new().fg(Color::Red).add_modifier(Modifier::BOLD),
+                )
+            } else if code_status.changed.get(i).copied().unwrap_or(false) {
+                ("▎", Style::new().fg(Color::Yellow))
+            } else {
+                (" ", Style::new())
+            };
+            let dim = match silenced.get(i).copied().unwrap_or(false) {
+                true => Style::new().add_modifier(Modifier::DIM),
+                false => Style::new(),
+            };
+            let mut spans = vec![
+                Span::styled(marker, marker_style),
+                Span::styled(
+                    format!("{:>gutter_width$} ", i + 1),
+                    Style::new().fg(Color::DarkGray),
+                ),
+            ];
+            spans.extend(
+                highlight_line(line)
+                    .into_iter()
+                    .map(|(token, text)| Span::styled(text, token_style(token).patch(dim))),
+            );
+            Line::from(spans)
+        })
+        .collect::<Vec<_>>();
+    f.render_widget(Paragraph::new(lines), inner);
+}
+
+fn token_style(token: Token) -> Style {
+    match token {
+        Token::Plain => Style::new(),
+        Token::Chain => Style::new().fg(Color::Cyan).add_modifier(Modifier::BOLD),
+        Token::Reference => Style::new().fg(Color::Magenta),
+        Token::Operator => Style::new().fg(Color::Yellow),
+        Token::Node => Style::new().fg(Color::Green),
+        Token::Number => Style::new().fg(Color::LightBlue),
+        Token::Comment => Style::new()
+            .fg(Color::DarkGray)
+            .add_modifier(Modifier::ITALIC),
+    }
+}
+
+fn render_tracks(f: &mut Frame<'_>, area: Rect, code_status: &CodeStatus, selected: Option<usize>) {
+    let names = code_status.source.as_deref().map_or(vec![], track_names);
+    let tracks = &code_status.tracks;
+    let any_solo = names.iter().any(|name| tracks.is_soloed(name));
+
+    let items = names
+        .iter()
+        .map(|name| {
+            let (muted, soloed) = (tracks.is_muted(name), tracks.is_soloed(name));
+            let style = match muted || (any_solo && !soloed) {
+                true => Style::new().fg(Color::DarkGray),
+                false => Style::new(),
+            };
+            ListItem::new(Line::from(vec![
+                Span::styled(
+                    if muted { "M" } else { "·" },
+                    Style::new().fg(Color::Red).add_modifier(Modifier::BOLD),
+                ),
+                Span::styled(
+                    if soloed { "S " } else { "· " },
+                    Style::new().fg(Color::Yellow).add_modifier(Modifier::BOLD),
+                ),
+                Span::styled(*name, style),
+            ]))
+        })
+        .collect::<Vec<_>>();
+
+    let title = match selected {
+        Some(_) => " tracks (m s esc) ",
+        None => " tracks (tab) ",
+    };
+    let list = List::new(items)
+        .block(
+            Block::bordered()
+                .title(title)
+                .border_set(border::ROUNDED)
+                .border_style(match selected {
+                    Some(_) => Style::new().fg(Color::Yellow),
+                    None => Style::new(),
+                }),
+        )
+        .highlight_style(Style::new().add_modifier(Modifier::REVERSED));
+    let mut state = ListState::default().with_selected(selected);
+    f.render_stateful_widget(list, area, &mut state);
+}
+
+fn render_code_error(f: &mut Frame<'_>, area: Rect, code_error: &CodeError) {
+    let mut lines = vec![Line::styled(
+        code_error.to_string(),
+        Style::new().fg(Color::Red).add_modifier(Modifier::BOLD),
+    )];
+    if let (Some((line, col)), Some(content)) = (code_error.position, &code_error.line) {
+        let gutter = format!("{line} | ");
+        lines.push(Line::from(vec![
+            Span::styled(gutter.clone(), Style::new().fg(Color::Gray)),
+            Span::raw(content.as_str()),
+        ]));
+        lines.push(Line::styled(
+            format!("{}^", " ".repeat(gutter.len() + col - 1)),
+            Style::new().fg(Color::Red),
+        ));
+    }
+
+    let paragraph = Paragraph::new(lines).block(
+        Block::bordered()
+            .title(" code error, still playing the last valid code ")
+            .border_style(Style::new().fg(Color::Red))
+            .border_set(border::ROUNDED),
+    );
+    f.render_widget(paragraph, area);
+}
+
 fn render_console(f: &mut Frame<'_>, area: Rect, console_buffer: &ShareableRecentLinesBuffer) {
     let guard = console_buffer.0.lock().expect("poisoned lock");
 
@@ -0,0 +1,498 @@
+use std::{
+    fmt,
+    sync::{
+        mpsc::{self, RecvTimeoutError},
+        Arc, Mutex,
+    },
+    thread,
+    time::Duration,
+};
+
+use anyhow::{Context, Result};
+use glicol::{Engine, EngineError};
+use glicol_synth::Buffer;
+use pest::error::LineColLocation;
+use ringbuf::{HeapConsumer, HeapProducer, HeapRb};
+use tracing::{error, info};
+
+use crate::{
+    decode_engine_error, highlight::changed_lines, quantize::Quantize, samples,
+    tracks::TrackSwitches, BLOCK_SIZE,
+};
+
+/// Engines waiting to be swapped in, newer ones replace older ones
+const READY_CAPACITY: usize = 4;
+
+/// Engines swapped out by the callback, waiting to be dropped by the updater
+const RETIRED_CAPACITY: usize = 8;
+
+/// Errors of the playing engines waiting to be reported
+const ERRORS_CAPACITY: usize = 4;
+
+/// An engine swapped out by the callback with the chain blocks played from it
+type Retired = (Box<Engine<BLOCK_SIZE>>, Vec<[Buffer<BLOCK_SIZE>; 2]>);
+
+/// How often the updater drops retired engines when no code comes in
+const POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+/// Why some code was rejected
+#[derive(Clone, Debug)]
+pub(crate) struct CodeError {
+    pub message: String,
+    /// 1-based line and column, when known
+    pub position: Option<(usize, usize)>,
+    /// Content of the line at `position`
+    pub line: Option<String>,
+}
+
+impl CodeError {
+    fn new(error: EngineError, code: &str) -> Self {
+        let mut error = Self::without_line(error, code);
+        error.line = error
+            .position
+            .and_then(|(line, _)| code.lines().nth(line - 1))
+            .map(str::to_owned);
+        error
+    }
+
+    fn without_line(error: EngineError, code: &str) -> Self {
+        match error {
+            EngineError::ParsingError(e) => {
+                let position = match e.line_col {
+                    LineColLocation::Pos(pos) => pos,
+                    LineColLocation::Span(start, _) => start,
+                };
+                Self {
+                    message: e.variant.message().into_owned(),
+                    position: Some(position),
+                    line: None,
+                }
+            }
+            EngineError::NonExsitSample(name) => Self {
+                position: find_position(code, &name),
+                message: format!("cannot use this non-exist samples {name}"),
+                line: None,
+            },
+            EngineError::NonExistReference(name) => Self {
+                position: find_position(code, &name),
+                message: format!("cannot use this non-exist reference {name}"),
+                line: None,
+            },
+        }
+    }
+}
+
+impl fmt::Display for CodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.position {
+            Some((line, col)) => write!(f, "line {line}, col {col}: {}", self.message),
+            None => f.write_str(&self.message),
+        }
+    }
+}
+
+/// 1-based line and column of the first occurrence of `needle`
+fn find_position(code: &str, needle: &str) -> Option<(usize, usize)> {
+    code.lines().enumerate().find_map(|(i, line)| {
+        line.find(needle)
+            .map(|byte| (i + 1, line[..byte].chars().count() + 1))
+    })
+}
+
+/// What the updater did with the latest code it got
+#[derive(Default)]
+pub(crate) struct CodeStatus {
+    /// Last valid code, handed to the audio thread
+    pub applied: Option<String>,
+    /// `applied` as it was written, before silencing the tracks switched off
+    pub source: Option<String>,
+    /// Lines of `applied` that weren't in the code it replaced
+    pub changed: Vec<bool>,
+    /// Why the latest code was rejected, cleared by the next valid one
+    pub error: Option<CodeError>,
+    /// Tracks muted or soloed, applied to the code before playing it
+    pub tracks: TrackSwitches,
+}
+
+/// How a new engine takes over from the playing one
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct Transition {
+    /// When to start playing it
+    pub quantize: Quantize,
+    /// Frames during which both engines play, fading from the old one to the new one
+    pub crossfade: usize,
+}
+
+/// An engine already running the new code, ready to replace the playing one
+pub(crate) struct PreparedEngine {
+    pub engine: Box<Engine<BLOCK_SIZE>>,
+    /// First block rendered while applying the code, to be played before calling the engine
+    pub first_block: [Buffer<BLOCK_SIZE>; 2],
+    /// Room for the blocks of the chains routed on their own, filled by the callback
+    pub chain_blocks: Vec<[Buffer<BLOCK_SIZE>; 2]>,
+    pub transition: Transition,
+}
+
+/// Audio callback side of the updater
+///
+/// Taking a prepared engine, retiring the old one and reporting errors never allocate or block.
+pub(crate) struct EngineHandoff {
+    ready: HeapConsumer<PreparedEngine>,
+    retired: HeapProducer<Retired>,
+    errors: HeapProducer<[u8; 256]>,
+}
+
+impl EngineHandoff {
+    /// Take the most recent prepared engine, if any
+    pub fn try_take(&mut self) -> Option<PreparedEngine> {
+        let mut latest = self.ready.pop()?;
+        while let Some(newer) = self.ready.pop() {
+            let older = std::mem::replace(&mut latest, newer);
+            self.retire(older.engine, older.chain_blocks);
+        }
+        Some(latest)
+    }
+
+    /// Hand an engine and its chain blocks back to the updater so they aren't deallocated on
+    /// the audio thread
+    pub fn retire(
+        &mut self,
+        engine: Box<Engine<BLOCK_SIZE>>,
+        chain_blocks: Vec<[Buffer<BLOCK_SIZE>; 2]>,
+    ) {
+        if let Err(retired) = self.retired.push((engine, chain_blocks)) {
+            // the updater is gone or late, nothing better to do than dropping it here
+            drop(retired);
+        }
+    }
+
+    /// Hand the error bytes returned by [`Engine::next_block`] to the updater to log and show
+    pub fn report_error(&mut self, raw_err: [u8; 256]) {
+        // a full queue already has errors to show
+        let _ = self.errors.push(raw_err);
+    }
+}
+
+/// Updater side of the [`EngineHandoff`]
+struct UpdaterHandoff {
+    ready: HeapProducer<PreparedEngine>,
+    retired: HeapConsumer<Retired>,
+    errors: HeapConsumer<[u8; 256]>,
+}
+
+/// Start the thread validating the code updates and preparing engines for the valid ones
+///
+/// Updates follow `transition`, unless their code has a quantize directive saying otherwise.
+/// Each engine comes with blocks for the `chains` routed on their own.
+pub(crate) fn spawn_updater(
+    code_updates: mpsc::Receiver<String>,
+    sr: usize,
+    bpm: f32,
+    transition: Transition,
+    chains: usize,
+) -> Result<(EngineHandoff, Arc<Mutex<CodeStatus>>)> {
+    let (ready_producer, ready) = HeapRb::new(READY_CAPACITY).split();
+    let (retired, retired_consumer) = HeapRb::new(RETIRED_CAPACITY).split();
+    let (errors, errors_consumer) = HeapRb::new(ERRORS_CAPACITY).split();
+    let updater_handoff = UpdaterHandoff {
+        ready: ready_producer,
+        retired: retired_consumer,
+        errors: errors_consumer,
+    };
+    let status = Arc::new(Mutex::new(CodeStatus::default()));
+
+    {
+        let status = status.clone();
+        thread::Builder::new()
+            .name(String::from("updater"))
+            .spawn(move || {
+                run_updater(
+                    code_updates,
+                    updater_handoff,
+                    &status,
+                    sr,
+                    bpm,
+                    transition,
+                    chains,
+                )
+            })
+            .context("spawn updater thread")?;
+    }
+
+    Ok((
+        EngineHandoff {
+            ready,
+            retired,
+            errors,
+        },
+        status,
+    ))
+}
+
+fn run_updater(
+    code_updates: mpsc::Receiver<String>,
+    handoff: UpdaterHandoff,
+    status: &Mutex<CodeStatus>,
+    sr: usize,
+    bpm: f32,
+    transition: Transition,
+    chains: usize,
+) {
+    // samples are leaked, so every engine can share them
+    let mut template = Engine::<BLOCK_SIZE>::new();
+    samples::load_samples_from_env(&mut template);
+
+    let UpdaterHandoff {
+        mut ready,
+        mut retired,
+        mut errors,
+    } = handoff;
+    let mut pending: Option<PreparedEngine> = None;
+
+    loop {
+        retired.clear();
+        report_engine_errors(&mut errors, status);
+
+        if let Some(prepared) = pending.take() {
+            if let Err(prepared) = ready.push(prepared) {
+                pending = Some(prepared);
+            }
+        }
+
+        let code = match code_updates.recv_timeout(POLL_INTERVAL) {
+            Ok(code) => code,
+            Err(RecvTimeoutError::Timeout) => continue,
+            Err(RecvTimeoutError::Disconnected) => return, // closing down
+        };
+
+        let (source, code) = {
+            let mut status = status.lock().expect("poisoned lock");
+            let played = status.tracks.apply(&code);
+            // rebuilding the same code would only restart what's playing
+            if status.applied.as_ref() == Some(&played) {
+                status.error = None;
+                continue;
+            }
+            (code, played)
+        };
+
+        let quantize = Quantize::from_directive(&code).unwrap_or(transition.quantize);
+        if quantize.for_code(&code) != quantize {
+            info!("the code has bar patterns, quantizing to the bar to keep them in phase");
+        }
+        let transition = Transition {
+            quantize: quantize.for_code(&code),
+            ..transition
+        };
+        let prepared = prepare_engine(&template, &code, sr, bpm, transition, chains);
+        let mut status = status.lock().expect("poisoned lock");
+        match prepared {
+            Ok(prepared) => {
+                match transition.quantize {
+                    Quantize::None => info!("code ready"),
+                    Quantize::Beat => info!("code ready, waiting for the next beat"),
+                    Quantize::Bar => info!("code ready, waiting for the next bar"),
+                }
+                pending = Some(prepared);
+                status.changed = match &status.applied {
+                    Some(old) => changed_lines(old, &code),
+                    None => vec![false; code.lines().count()],
+                };
+                status.applied = Some(code);
+                status.source = Some(source);
+                status.error = None;
+            }
+            Err(mut e) => {
+                // the line as written, without the `>> mul 0` of a silenced track
+                if let Some((line, _)) = e.position {
+                    e.line = source.lines().nth(line - 1).map(str::to_owned);
+                }
+                error!("invalid code, keep playing the last valid one: {e}");
+                status.error = Some(e);
+            }
+        }
+    }
+}
+
+/// Log the errors the playing engines ran into and show the latest one
+fn report_engine_errors(errors: &mut HeapConsumer<[u8; 256]>, status: &Mutex<CodeStatus>) {
+    while let Some(raw_err) = errors.pop() {
+        if let Some(message) = decode_engine_error(&raw_err) {
+            error!("get next block of engine: {message}");
+            status.lock().expect("poisoned lock").error = Some(CodeError {
+                message,
+                position: None,
+                line: None,
+            });
+        }
+    }
+}
+
+/// Build a new engine running `code`, keeping the samples known by `template`, with blocks for
+/// the `chains` routed on their own
+fn prepare_engine(
+    template: &Engine<BLOCK_SIZE>,
+    code: &str,
+    sr: usize,
+    bpm: f32,
+    transition: Transition,
+    chains: usize,
+) -> Result<PreparedEngine, CodeError> {
+    let mut engine = Box::new(Engine::<BLOCK_SIZE>::new());
+    engine.samples_dict.clone_from(&template.samples_dict);
+    engine.set_sr(sr);
+    engine.set_bpm(bpm);
+    engine.update_with_code(code);
+    engine.update().map_err(|e| CodeError::new(e, code))?;
+
+    // the engine applies the code again on its first block, it's a no-op as nothing changed
+    engine.livecoding = false;
+    let (block, _) = engine.next_block(vec![]);
+    let first_block = [block[0].clone(), block[1].clone()];
+
+    Ok(PreparedEngine {
+        engine,
+        first_block,
+        chain_blocks: vec![[Buffer::SILENT; 2]; chains],
+        transition,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        find_position, prepare_engine, report_engine_errors, spawn_updater, CodeStatus, Transition,
+    };
+
+    use std::{
+        sync::{mpsc, Mutex},
+        thread,
+        time::{Duration, Instant},
+    };
+
+    use glicol::Engine;
+    use ringbuf::HeapRb;
+
+    use crate::{quantize::Quantize, BLOCK_SIZE};
+
+    const TRANSITION: Transition = Transition {
+        quantize: Quantize::Bar,
+        crossfade: 0,
+    };
+
+    /// Wait for the updater to get there, failing after a while
+    fn wait_until(status: &Mutex<CodeStatus>, done: impl Fn(&CodeStatus) -> bool) {
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while !done(&status.lock().unwrap()) {
+            assert!(Instant::now() < deadline, "the updater took too long");
+            thread::sleep(Duration::from_millis(5));
+        }
+    }
+
+    #[test]
+    fn prepare_plays_from_first_block() {
+        let template = Engine::<BLOCK_SIZE>::new();
+        let prepared =
+            prepare_engine(&template, "o: sin 440", 44100, 120.0, TRANSITION, 2).unwrap();
+
+        assert!(prepared.first_block[0].iter().any(|s| *s != 0.0));
+        assert_eq!(prepared.chain_blocks.len(), 2);
+    }
+
+    #[test]
+    fn locate_syntax_errors() {
+        let template = Engine::<BLOCK_SIZE>::new();
+        let Err(e) = prepare_engine(
+            &template,
+            "o: sin 440\n\n~a: sin >>",
+            44100,
+            120.0,
+            TRANSITION,
+            0,
+        ) else {
+            panic!("invalid code accepted");
+        };
+
+        assert_eq!(e.position.map(|(line, _)| line), Some(3));
+    }
+
+    #[test]
+    fn locate_missing_references() {
+        let template = Engine::<BLOCK_SIZE>::new();
+        let Err(e) = prepare_engine(
+            &template,
+            "o: sin 440\n>> mul ~amp",
+            44100,
+            120.0,
+            TRANSITION,
+            0,
+        ) else {
+            panic!("invalid code accepted");
+        };
+
+        assert_eq!(e.position, Some((2, 8)));
+        assert_eq!(e.line.as_deref(), Some(">> mul ~amp"));
+        assert_eq!(find_position("aé ~b", "~b"), Some((1, 4)));
+    }
+
+    #[test]
+    fn show_errors_of_playing_engines() {
+        let (mut producer, mut errors) = HeapRb::new(4).split();
+        let status = Mutex::new(CodeStatus::default());
+
+        let mut raw_err = [0; 256];
+        raw_err[0] = 3;
+        raw_err[2..10].copy_from_slice(b"no ~amp!");
+        producer.push([0; 256]).unwrap();
+        producer.push(raw_err).unwrap();
+        report_engine_errors(&mut errors, &status);
+
+        let error = status.lock().unwrap().error.clone().unwrap();
+        assert_eq!(error.to_string(), "no ~amp!");
+        assert!(errors.is_empty());
+    }
+
+    #[test]
+    fn hand_over_latest_engine() {
+        let (sender, receiver) = mpsc::channel();
+        let (mut handoff, status) = spawn_updater(receiver, 44100, 120.0, TRANSITION, 0).unwrap();
+
+        sender.send(String::from("o: sin 440")).unwrap();
+        sender.send(String::from("o: sin 220")).unwrap();
+        sender.send(String::from("o: sin")).unwrap();
+        // the invalid code comes last, once the others are handed over
+        wait_until(&status, |status| status.error.is_some());
+
+        {
+            let status = status.lock().unwrap();
+            assert_eq!(status.applied.as_deref(), Some("o: sin 220"));
+            assert_eq!(status.changed, [true]);
+            assert!(status.error.is_some());
+        }
+
+        let mut prepared = handoff.try_take().unwrap();
+        let (block, _) = prepared.engine.next_block(vec![]);
+        let expected = (2.0 * std::f32::consts::PI * 220.0 * BLOCK_SIZE as f32 / 44100.0).sin();
+        assert!((block[0][0] - expected).abs() < 1e-3);
+        assert!(handoff.try_take().is_none());
+
+        handoff.retire(prepared.engine, prepared.chain_blocks);
+    }
+
+    #[test]
+    fn play_muted_tracks_silenced() {
+        let (sender, receiver) = mpsc::channel();
+        let (_handoff, status) = spawn_updater(receiver, 44100, 120.0, TRANSITION, 0).unwrap();
+
+        status.lock().unwrap().tracks.toggle_mute("~a");
+        sender.send(String::from("~a: sin 440\no: mix ~a")).unwrap();
+        wait_until(&status, |status| status.applied.is_some());
+
+        let status = status.lock().unwrap();
+        assert_eq!(status.source.as_deref(), Some("~a: sin 440\no: mix ~a"));
+        assert_eq!(
+            status.applied.as_deref(),
+            Some("~a: sin 440 >> mul 0\no: mix ~a")
+        );
+    }
+}
@@ -8,15 +8,15 @@ use notify::{
 };
 use tracing::{debug, error, info};
 
-/// Watch the given file at path and stream back its content
+/// Watch the given file at path and stream its content to `sender`
 ///
 /// Fails to detected when the path is replaced by an empty file
 pub(crate) fn watch_path(
     path: &Path,
-) -> Result<(impl notify::Watcher, sync::mpsc::Receiver<String>)> {
+    sender: sync::mpsc::Sender<String>,
+) -> Result<impl notify::Watcher> {
     // Event's paths are absolute
     let path = path.canonicalize().context("canonicalize file path")?;
-    let (sender, receiver) = sync::mpsc::channel();
 
     let content = fs::read_to_string(&path).context("initial file read")?;
     sender.send(content).unwrap(); // receiver is still there
@@ -34,8 +34,10 @@ pub(crate) fn watch_path(
                             )
                         ) =>
                 {
-
-                    info!("🔥 CHANGE DETECTED AT 👉{} ✅ NOW DOING UPDATE 🚀", Local::now().format("%H:%M:%S"));
+                    info!(
+                        "🔥 CHANGE DETECTED AT 👉{} ✅ NOW DOING UPDATE 🚀",
+                        Local::now().format("%H:%M:%S")
+                    );
 
                     match fs::read_to_string(&path) {
                         Ok(code) => {
@@ -60,7 +62,7 @@ pub(crate) fn watch_path(
         )
         .context("add parent directory watch")?;
 
-    Ok((watcher, receiver))
+    Ok(watcher)
 }
 
 #[cfg(test)]
@@ -70,18 +72,24 @@ mod tests {
     use std::{
         fs::{self, File},
         io::Write,
-        sync::mpsc::TryRecvError,
+        path::Path,
+        sync::mpsc::{self, Receiver, TryRecvError},
     };
 
     use tempfile::TempDir;
 
+    fn watch(path: &Path) -> (impl notify::Watcher, Receiver<String>) {
+        let (sender, receiver) = mpsc::channel();
+        (watch_path(path, sender).unwrap(), receiver)
+    }
+
     #[test]
     fn show_initial_content() {
         let dir = TempDir::new().unwrap();
         let file = dir.path().join("file");
         fs::write(&file, "initial").unwrap();
 
-        let (_watcher, changes) = watch_path(&file).unwrap();
+        let (_watcher, changes) = watch(&file);
         assert_eq!(changes.recv().unwrap(), "initial");
 
         assert_eq!(changes.try_recv(), Err(TryRecvError::Empty));
@@ -93,7 +101,7 @@ mod tests {
         let file = dir.path().join("file");
         fs::write(&file, "initial").unwrap();
 
-        let (_watcher, changes) = watch_path(&file).unwrap();
+        let (_watcher, changes) = watch(&file);
         assert_eq!(changes.recv().unwrap(), "initial");
 
         fs::read(file).unwrap();
@@ -107,7 +115,7 @@ mod tests {
         let file = dir.path().join("file");
         fs::write(&file, "initial").unwrap();
 
-        let (_watcher, changes) = watch_path(&file).unwrap();
+        let (_watcher, changes) = watch(&file);
         assert_eq!(changes.recv().unwrap(), "initial");
 
         fs::remove_file(&file).unwrap();
@@ -123,7 +131,7 @@ mod tests {
         let file = dir.path().join("file");
         fs::write(&file, "initial").unwrap();
 
-        let (_watcher, changes) = watch_path(&file).unwrap();
+        let (_watcher, changes) = watch(&file);
         assert_eq!(changes.recv().unwrap(), "initial");
 
         let other_file = dir.path().join("other file");
@@ -140,7 +148,7 @@ mod tests {
         let file = dir.path().join("file");
         fs::write(&file, "initial").unwrap();
 
-        let (_watcher, changes) = watch_path(&file).unwrap();
+        let (_watcher, changes) = watch(&file);
         assert_eq!(changes.recv().unwrap(), "initial");
 
         {
@@ -163,7 +171,7 @@ mod tests {
         let file = dir.path().join("file");
         fs::write(&file, "initial").unwrap();
 
-        let (_watcher, changes) = watch_path(&file).unwrap();
+        let (_watcher, changes) = watch(&file);
         assert_eq!(changes.recv().unwrap(), "initial");
 
         let mut openned = File::create(&file).unwrap();
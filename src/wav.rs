@@ -0,0 +1,249 @@
+use std::io::{self, Seek, SeekFrom, Write};
+
+/// Sample encoding used in the data chunk of a WAV file
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum)]
+pub(crate) enum WavFormat {
+    /// 16-bit signed integer PCM
+    I16,
+    /// 24-bit signed integer PCM
+    I24,
+    /// 32-bit IEEE float
+    F32,
+}
+
+impl WavFormat {
+    fn bytes_per_sample(self) -> u16 {
+        match self {
+            WavFormat::I16 => 2,
+            WavFormat::I24 => 3,
+            WavFormat::F32 => 4,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct WavSpec {
+    pub channels: u16,
+    pub sample_rate: u32,
+    pub format: WavFormat,
+}
+
+impl WavSpec {
+    fn header_len(&self) -> u32 {
+        match self.format {
+            WavFormat::F32 => 58,
+            _ => 44,
+        }
+    }
+
+    /// Most frames a file can hold, its chunk sizes being 32 bits
+    pub fn max_frames(&self) -> u32 {
+        (u32::MAX - self.header_len()) / (self.channels * self.format.bytes_per_sample()) as u32
+    }
+}
+
+/// Streaming WAV writer, chunk sizes are patched in on [`WavWriter::finalize`]
+pub(crate) struct WavWriter<W: Write + Seek> {
+    writer: W,
+    spec: WavSpec,
+    /// Offset of the `fact` chunk sample count, only written for float data
+    fact_pos: Option<u64>,
+    data_pos: u64,
+    data_len: u32,
+}
+
+impl<W: Write + Seek> WavWriter<W> {
+    pub fn new(mut writer: W, spec: WavSpec) -> io::Result<Self> {
+        let block_align = spec.channels * spec.format.bytes_per_sample();
+        let is_float = spec.format == WavFormat::F32;
+
+        writer.write_all(b"RIFF")?;
+        writer.write_all(&0u32.to_le_bytes())?; // patched on finalize
+        writer.write_all(b"WAVE")?;
+
+        writer.write_all(b"fmt ")?;
+        writer.write_all(&(if is_float { 18u32 } else { 16u32 }).to_le_bytes())?;
+        writer.write_all(&(if is_float { 3u16 } else { 1u16 }).to_le_bytes())?;
+        writer.write_all(&spec.channels.to_le_bytes())?;
+        writer.write_all(&spec.sample_rate.to_le_bytes())?;
+        writer.write_all(&(spec.sample_rate * block_align as u32).to_le_bytes())?;
+        writer.write_all(&block_align.to_le_bytes())?;
+        writer.write_all(&(spec.format.bytes_per_sample() * 8).to_le_bytes())?;
+
+        // non-PCM formats need an extension size and a fact chunk
+        let fact_pos = if is_float {
+            writer.write_all(&0u16.to_le_bytes())?;
+            writer.write_all(b"fact")?;
+            writer.write_all(&4u32.to_le_bytes())?;
+            let pos = writer.stream_position()?;
+            writer.write_all(&0u32.to_le_bytes())?;
+            Some(pos)
+        } else {
+            None
+        };
+
+        writer.write_all(b"data")?;
+        writer.write_all(&0u32.to_le_bytes())?;
+        let data_pos = writer.stream_position()?;
+
+        Ok(Self {
+            writer,
+            spec,
+            fact_pos,
+            data_pos,
+            data_len: 0,
+        })
+    }
+
+    /// Write interleaved samples, values outside of [-1, 1] are clipped for integer formats
+    ///
+    /// Fails without writing anything once the file would go over the 4 GiB WAV files can hold.
+    pub fn write_samples(&mut self, samples: &[f32]) -> io::Result<()> {
+        let data_len = u32::try_from(samples.len())
+            .ok()
+            .and_then(|len| len.checked_mul(self.spec.format.bytes_per_sample() as u32))
+            .and_then(|len| len.checked_add(self.data_len))
+            .filter(|len| *len <= u32::MAX - self.spec.header_len())
+            .ok_or_else(|| io::Error::new(io::ErrorKind::FileTooLarge, "WAV file over 4 GiB"))?;
+
+        for &sample in samples {
+            match self.spec.format {
+                WavFormat::I16 => {
+                    let value = (sample.clamp(-1.0, 1.0) * i16::MAX as f32).round() as i16;
+                    self.writer.write_all(&value.to_le_bytes())?;
+                }
+                WavFormat::I24 => {
+                    let value = (sample.clamp(-1.0, 1.0) * 8_388_607.0).round() as i32;
+                    self.writer.write_all(&value.to_le_bytes()[..3])?;
+                }
+                WavFormat::F32 => self.writer.write_all(&sample.to_le_bytes())?,
+            }
+        }
+        self.data_len = data_len;
+
+        Ok(())
+    }
+
+    /// Number of frames written so far
+    pub fn frames(&self) -> u32 {
+        self.data_len / (self.spec.channels * self.spec.format.bytes_per_sample()) as u32
+    }
+
+    /// Patch the chunk sizes for the data written so far, keeping the file readable if the
+    /// process is killed before [`WavWriter::finalize`]
+    pub fn update_header(&mut self) -> io::Result<()> {
+        let end = self.writer.stream_position()?;
+
+        self.writer.seek(SeekFrom::Start(4))?;
+        self.writer.write_all(&((end - 8) as u32).to_le_bytes())?;
+
+        if let Some(fact_pos) = self.fact_pos {
+            let frames = self.frames();
+            self.writer.seek(SeekFrom::Start(fact_pos))?;
+            self.writer.write_all(&frames.to_le_bytes())?;
+        }
+
+        self.writer.seek(SeekFrom::Start(self.data_pos - 4))?;
+        self.writer.write_all(&self.data_len.to_le_bytes())?;
+
+        self.writer.seek(SeekFrom::Start(end))?;
+        self.writer.flush()
+    }
+
+    /// Patch the chunk sizes and return the inner writer
+    pub fn finalize(mut self) -> io::Result<W> {
+        // chunks are word aligned
+        if self.data_len % 2 == 1 {
+            self.writer.write_all(&[0])?;
+        }
+        self.update_header()?;
+
+        Ok(self.writer)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{WavFormat, WavSpec, WavWriter};
+
+    use std::io::Cursor;
+
+    fn u32_at(bytes: &[u8], pos: usize) -> u32 {
+        u32::from_le_bytes(bytes[pos..pos + 4].try_into().unwrap())
+    }
+
+    #[test]
+    fn pcm16_header_and_data() {
+        let spec = WavSpec {
+            channels: 2,
+            sample_rate: 48000,
+            format: WavFormat::I16,
+        };
+        let mut writer = WavWriter::new(Cursor::new(Vec::new()), spec).unwrap();
+        writer.write_samples(&[0.0, 1.0, -1.0, 2.0]).unwrap();
+        assert_eq!(writer.frames(), 2);
+        let bytes = writer.finalize().unwrap().into_inner();
+
+        assert_eq!(&bytes[0..4], b"RIFF");
+        assert_eq!(u32_at(&bytes, 4) as usize, bytes.len() - 8);
+        assert_eq!(&bytes[36..40], b"data");
+        assert_eq!(u32_at(&bytes, 40), 8);
+        assert_eq!(
+            &bytes[44..],
+            &[0, 0, 0xff, 0x7f, 0x01, 0x80, 0xff, 0x7f] // 2.0 is clipped
+        );
+    }
+
+    #[test]
+    fn pcm24_is_padded() {
+        let spec = WavSpec {
+            channels: 1,
+            sample_rate: 44100,
+            format: WavFormat::I24,
+        };
+        let mut writer = WavWriter::new(Cursor::new(Vec::new()), spec).unwrap();
+        writer.write_samples(&[-1.0]).unwrap();
+        let bytes = writer.finalize().unwrap().into_inner();
+
+        assert_eq!(u32_at(&bytes, 40), 3);
+        assert_eq!(&bytes[44..47], &[0x01, 0x00, 0x80]);
+        assert_eq!(bytes.len(), 48);
+        assert_eq!(u32_at(&bytes, 4) as usize, bytes.len() - 8);
+    }
+
+    #[test]
+    fn float_has_fact_chunk() {
+        let spec = WavSpec {
+            channels: 2,
+            sample_rate: 44100,
+            format: WavFormat::F32,
+        };
+        let mut writer = WavWriter::new(Cursor::new(Vec::new()), spec).unwrap();
+        writer.write_samples(&[0.5, -0.5, 0.25, -0.25]).unwrap();
+        let bytes = writer.finalize().unwrap().into_inner();
+
+        assert_eq!(u32_at(&bytes, 16), 18);
+        assert_eq!(&bytes[38..42], b"fact");
+        assert_eq!(u32_at(&bytes, 46), 2);
+        assert_eq!(&bytes[50..54], b"data");
+        assert_eq!(u32_at(&bytes, 54), 16);
+        assert_eq!(f32::from_le_bytes(bytes[58..62].try_into().unwrap()), 0.5);
+    }
+
+    #[test]
+    fn refuse_data_over_4_gib() {
+        let spec = WavSpec {
+            channels: 2,
+            sample_rate: 44100,
+            format: WavFormat::I16,
+        };
+        let mut writer = WavWriter::new(Cursor::new(Vec::new()), spec).unwrap();
+        writer.data_len = (spec.max_frames() - 1) * 4;
+        writer.write_samples(&[0.5, -0.5]).unwrap();
+        assert_eq!(writer.frames(), spec.max_frames());
+
+        let e = writer.write_samples(&[0.5, -0.5]).unwrap_err();
+        assert_eq!(e.kind(), std::io::ErrorKind::FileTooLarge);
+        assert_eq!(writer.frames(), spec.max_frames());
+    }
+}